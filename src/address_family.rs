@@ -1,13 +1,24 @@
 use super::MDNS_PORT;
-use net2::UdpBuilder;
+use net2::{UdpBuilder, UdpSocketExt};
 #[cfg(not(windows))]
 use net2::unix::UnixUdpBuilderExt;
 use std::io;
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
 
+use crate::interface::Interface;
+
 pub enum Inet {}
 pub enum Inet6 {}
 
+/// Where a received packet was headed, as far as the platform reports it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PacketInfo {
+    /// the address the packet was sent to
+    pub dst: IpAddr,
+    /// index of the interface the packet arrived on
+    pub ifindex: u32,
+}
+
 pub trait AddressFamily {
     fn bind() -> io::Result<UdpSocket> {
         let addr = SocketAddr::new(Self::any_addr(), MDNS_PORT);
@@ -15,18 +26,29 @@ pub trait AddressFamily {
         builder.reuse_address(true)?;
         #[cfg(not(windows))]
         let _ = builder.reuse_port(true);
-        let socket = builder.bind(addr)?;
-        Self::join_multicast(&socket)?;
-        Ok(socket)
+        builder.bind(addr)
     }
 
     fn socket_builder() -> io::Result<UdpBuilder>;
     fn any_addr() -> IpAddr;
     fn mdns_group() -> IpAddr;
-    fn join_multicast(socket: &UdpSocket) -> io::Result<()>;
+    /// Joins the mDNS group on `iface`, or on the system's default multicast
+    /// interface if `None`.
+    fn join_multicast(socket: &UdpSocket, iface: Option<&Interface>) -> io::Result<()>;
+    /// Leaves the mDNS group on `iface`.
+    fn leave_multicast(socket: &UdpSocket, iface: &Interface) -> io::Result<()>;
+    /// Makes multicasts from `socket` go out of `iface`.
+    fn set_multicast_if(socket: &UdpSocket, iface: &Interface) -> io::Result<()>;
     fn v6() -> bool;
 }
 
+fn no_ipv4(iface: &Interface) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::AddrNotAvailable,
+        format!("{} has no IPv4 address", iface.name),
+    )
+}
+
 impl AddressFamily for Inet {
     fn socket_builder() -> io::Result<UdpBuilder> {
         UdpBuilder::new_v4()
@@ -37,8 +59,20 @@ impl AddressFamily for Inet {
     fn mdns_group() -> IpAddr {
         IpAddr::V4(Ipv4Addr::new(224, 0, 0, 251))
     }
-    fn join_multicast(socket: &UdpSocket) -> io::Result<()> {
-        socket.join_multicast_v4(&Ipv4Addr::new(224, 0, 0, 251), &Ipv4Addr::new(0, 0, 0, 0))
+    fn join_multicast(socket: &UdpSocket, iface: Option<&Interface>) -> io::Result<()> {
+        let addr = match iface {
+            Some(iface) => iface.ipv4().ok_or_else(|| no_ipv4(iface))?,
+            None => Ipv4Addr::new(0, 0, 0, 0),
+        };
+        socket.join_multicast_v4(&Ipv4Addr::new(224, 0, 0, 251), &addr)
+    }
+    fn leave_multicast(socket: &UdpSocket, iface: &Interface) -> io::Result<()> {
+        let addr = iface.ipv4().ok_or_else(|| no_ipv4(iface))?;
+        socket.leave_multicast_v4(&Ipv4Addr::new(224, 0, 0, 251), &addr)
+    }
+    fn set_multicast_if(socket: &UdpSocket, iface: &Interface) -> io::Result<()> {
+        let addr = iface.ipv4().ok_or_else(|| no_ipv4(iface))?;
+        socket.set_multicast_if_v4(&addr)
     }
     fn v6() -> bool {
         false
@@ -55,8 +89,15 @@ impl AddressFamily for Inet6 {
     fn mdns_group() -> IpAddr {
         IpAddr::V6(Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 0xfb))
     }
-    fn join_multicast(socket: &UdpSocket) -> io::Result<()> {
-        socket.join_multicast_v6(&Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 0xfb), 0)
+    fn join_multicast(socket: &UdpSocket, iface: Option<&Interface>) -> io::Result<()> {
+        let index = iface.map_or(0, |iface| iface.index);
+        socket.join_multicast_v6(&Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 0xfb), index)
+    }
+    fn leave_multicast(socket: &UdpSocket, iface: &Interface) -> io::Result<()> {
+        socket.leave_multicast_v6(&Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 0xfb), iface.index)
+    }
+    fn set_multicast_if(socket: &UdpSocket, iface: &Interface) -> io::Result<()> {
+        socket.set_multicast_if_v6(iface.index)
     }
     fn v6() -> bool {
         true
@@ -0,0 +1,406 @@
+//! Choosing which addresses go into the host's address records.
+//!
+//! An interface often has more addresses than are worth advertising: IPv6
+//! temporary addresses come and go, deprecated ones are only kept for
+//! existing connections, and an IPv4 link-local address is useless to a peer
+//! that reaches us over a routable one. An [`AddressPolicy`] looks at the
+//! addresses of one interface at a time and picks the ones to advertise.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::net::{IpAddr, Ipv6Addr};
+use std::sync::Arc;
+
+use crate::interface::Interface;
+
+/// An address the responder could advertise, as handed to an
+/// [`AddressPolicy`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Address {
+    pub ip: IpAddr,
+    /// Name of the interface the address is assigned to.
+    pub interface: String,
+    /// Whether this is a temporary IPv6 address generated for privacy
+    /// (RFC 8981), which is replaced every so often.
+    pub temporary: bool,
+    /// Whether this is an IPv6 address whose preferred lifetime ran out,
+    /// which is only kept for existing connections.
+    pub deprecated: bool,
+}
+
+/// What the system reports about an IPv6 address beyond the address itself.
+/// Where it reports nothing, addresses count as neither.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) struct Ipv6Flags {
+    pub temporary: bool,
+    pub deprecated: bool,
+}
+
+type Select = dyn Fn(&[Address]) -> Vec<IpAddr> + Send + Sync;
+
+/// Decides which addresses go into the host's A and AAAA records, set with
+/// [`Config::address_policy`](struct.Config.html#method.address_policy).
+///
+/// A policy is handed the addresses of one interface at a time and returns
+/// those to advertise. The default is [`stable`](#method.stable).
+///
+/// # Example
+///
+/// ```no_run
+/// use mdns_responder_rs as mdns;
+///
+/// # fn main() -> std::io::Result<()> {
+/// let policy = mdns::AddressPolicy::stable().max_aaaa(2);
+/// let config = mdns::Config::new().address_policy(policy);
+/// let responder = mdns::Responder::with_config(config)?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct AddressPolicy {
+    select: Arc<Select>,
+    max_aaaa: Option<usize>,
+}
+
+impl AddressPolicy {
+    /// Advertises every address.
+    pub fn all() -> AddressPolicy {
+        AddressPolicy::custom(|candidates| candidates.iter().map(|addr| addr.ip).collect())
+    }
+
+    /// Advertises the addresses peers can rely on:
+    ///
+    /// - deprecated IPv6 addresses are left out;
+    /// - temporary IPv6 addresses are only advertised if the interface has
+    ///   no stable routable IPv6 address;
+    /// - IPv4 link-local addresses (169.254.0.0/16) are only advertised if
+    ///   the interface has no routable IPv4 address.
+    pub fn stable() -> AddressPolicy {
+        AddressPolicy::custom(stable)
+    }
+
+    /// Lets `select` pick the addresses to advertise from those of an
+    /// interface. Addresses it returns that aren't among them are ignored.
+    pub fn custom<F>(select: F) -> AddressPolicy
+    where
+        F: Fn(&[Address]) -> Vec<IpAddr> + Send + Sync + 'static,
+    {
+        AddressPolicy {
+            select: Arc::new(select),
+            max_aaaa: None,
+        }
+    }
+
+    /// Advertises at most `max` IPv6 addresses per interface out of those the
+    /// policy picks, preferring global addresses over unique local ones, and
+    /// those over link-local ones.
+    pub fn max_aaaa(mut self, max: usize) -> AddressPolicy {
+        self.max_aaaa = Some(max);
+        self
+    }
+
+    /// Returns the addresses to advertise out of `candidates`, which belong
+    /// to a single interface.
+    pub(crate) fn select(&self, candidates: &[Address]) -> Vec<IpAddr> {
+        let selected = (self.select)(candidates);
+        let selected = candidates
+            .iter()
+            .map(|addr| addr.ip)
+            .filter(|ip| selected.contains(ip));
+
+        let (v4, mut v6): (Vec<IpAddr>, Vec<IpAddr>) = selected.partition(IpAddr::is_ipv4);
+        if let Some(max) = self.max_aaaa {
+            v6.sort_by_key(|ip| match *ip {
+                IpAddr::V6(ip) => ipv6_rank(ip),
+                IpAddr::V4(_) => 0,
+            });
+            v6.truncate(max);
+        }
+        v4.into_iter().chain(v6).collect()
+    }
+}
+
+impl Default for AddressPolicy {
+    fn default() -> AddressPolicy {
+        AddressPolicy::stable()
+    }
+}
+
+impl fmt::Debug for AddressPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("AddressPolicy")
+            .field("max_aaaa", &self.max_aaaa)
+            .finish()
+    }
+}
+
+/// Returns the addresses of `iface` as candidates for a policy, with the
+/// flags the system reported for its IPv6 addresses.
+pub(crate) fn candidates(iface: &Interface, flags: &HashMap<Ipv6Addr, Ipv6Flags>) -> Vec<Address> {
+    iface
+        .addrs
+        .iter()
+        .map(|&ip| {
+            let flags = match ip {
+                IpAddr::V6(ip) => flags.get(&ip).cloned().unwrap_or_default(),
+                IpAddr::V4(_) => Ipv6Flags::default(),
+            };
+            Address {
+                ip,
+                interface: iface.name.clone(),
+                temporary: flags.temporary,
+                deprecated: flags.deprecated,
+            }
+        })
+        .collect()
+}
+
+/// Addresses an interface stopped and started advertising.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub(crate) struct Changes {
+    pub removed: Vec<IpAddr>,
+    pub added: Vec<IpAddr>,
+}
+
+/// Compares the addresses advertised on each interface before and after the
+/// interfaces changed, both as picked by the policy, and returns what changed
+/// by interface index. Interfaces that came or went add or remove all of
+/// theirs.
+pub(crate) fn changes(before: &[Interface], after: &[Interface]) -> HashMap<u32, Changes> {
+    let addrs = |list: &[Interface], index: u32| -> Vec<IpAddr> {
+        list.iter()
+            .filter(|iface| iface.index == index)
+            .flat_map(|iface| iface.addrs.iter().cloned())
+            .collect()
+    };
+
+    let mut changes = HashMap::new();
+    for index in before.iter().chain(after).map(|iface| iface.index) {
+        if changes.contains_key(&index) {
+            continue;
+        }
+        let old = addrs(before, index);
+        let new = addrs(after, index);
+        let change = Changes {
+            removed: old.iter().filter(|ip| !new.contains(ip)).cloned().collect(),
+            added: new.iter().filter(|ip| !old.contains(ip)).cloned().collect(),
+        };
+        changes.insert(index, change);
+    }
+    changes.retain(|_, change| !change.removed.is_empty() || !change.added.is_empty());
+    changes
+}
+
+fn stable(candidates: &[Address]) -> Vec<IpAddr> {
+    let routable_v4 = candidates.iter().any(|addr| match addr.ip {
+        IpAddr::V4(ip) => !ip.is_link_local(),
+        IpAddr::V6(_) => false,
+    });
+    let stable_v6 = candidates.iter().any(|addr| match addr.ip {
+        IpAddr::V6(ip) => !is_link_local(ip) && !addr.temporary && !addr.deprecated,
+        IpAddr::V4(_) => false,
+    });
+
+    candidates
+        .iter()
+        .filter(|addr| match addr.ip {
+            IpAddr::V4(ip) => !ip.is_link_local() || !routable_v4,
+            IpAddr::V6(_) => !addr.deprecated && (!addr.temporary || !stable_v6),
+        })
+        .map(|addr| addr.ip)
+        .collect()
+}
+
+fn is_link_local(ip: Ipv6Addr) -> bool {
+    ip.segments()[0] & 0xffc0 == 0xfe80
+}
+
+/// Orders IPv6 addresses from the most to the least widely reachable:
+/// global, unique local (fc00::/7), then link-local.
+fn ipv6_rank(ip: Ipv6Addr) -> u8 {
+    if is_link_local(ip) {
+        2
+    } else if ip.segments()[0] & 0xfe00 == 0xfc00 {
+        1
+    } else {
+        0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    fn addr(ip: &str, temporary: bool, deprecated: bool) -> Address {
+        Address {
+            ip: ip.parse().unwrap(),
+            interface: "eth0".to_owned(),
+            temporary,
+            deprecated,
+        }
+    }
+
+    fn ips(list: &[&str]) -> Vec<IpAddr> {
+        list.iter().map(|ip| ip.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn test_all() {
+        let candidates = vec![
+            addr("169.254.3.4", false, false),
+            addr("2001:db8::1", true, true),
+        ];
+        let selected = AddressPolicy::all().select(&candidates);
+        assert_eq!(selected, ips(&["169.254.3.4", "2001:db8::1"]));
+    }
+
+    #[test]
+    fn test_stable() {
+        let candidates = vec![
+            addr("192.168.1.2", false, false),
+            addr("169.254.3.4", false, false),
+            addr("2001:db8::1", false, false),
+            addr("2001:db8::abcd", true, false),
+            addr("2001:db8::dead", false, true),
+            addr("fe80::1", false, false),
+        ];
+        let selected = AddressPolicy::stable().select(&candidates);
+        assert_eq!(selected, ips(&["192.168.1.2", "2001:db8::1", "fe80::1"]));
+    }
+
+    #[test]
+    fn test_stable_fallbacks() {
+        let candidates = vec![
+            addr("169.254.3.4", false, false),
+            addr("2001:db8::abcd", true, false),
+            addr("fe80::1", false, false),
+        ];
+        let selected = AddressPolicy::stable().select(&candidates);
+        assert_eq!(selected, ips(&["169.254.3.4", "2001:db8::abcd", "fe80::1"]));
+    }
+
+    #[test]
+    fn test_max_aaaa() {
+        let candidates = vec![
+            addr("fe80::1", false, false),
+            addr("fd00::1", false, false),
+            addr("192.168.1.2", false, false),
+            addr("2001:db8::1", false, false),
+        ];
+        let selected = AddressPolicy::all().max_aaaa(2).select(&candidates);
+        assert_eq!(selected, ips(&["192.168.1.2", "2001:db8::1", "fd00::1"]));
+    }
+
+    #[test]
+    fn test_custom() {
+        let candidates = vec![
+            addr("192.168.1.2", false, false),
+            addr("fe80::1", false, false),
+        ];
+        let policy = AddressPolicy::custom(|candidates| {
+            let mut selected: Vec<IpAddr> = candidates
+                .iter()
+                .filter(|addr| addr.ip.is_ipv4())
+                .map(|addr| addr.ip)
+                .collect();
+            // not one of ours, so it's ignored
+            selected.push(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)));
+            selected
+        });
+        assert_eq!(policy.select(&candidates), ips(&["192.168.1.2"]));
+    }
+
+    /// Returns eth0 with the addresses the stable policy picks out of `addrs`,
+    /// where those listed in `temporary` and `deprecated` are flagged so.
+    fn advertised(addrs: &[&str], temporary: &[&str], deprecated: &[&str]) -> Interface {
+        let iface = Interface {
+            name: "eth0".to_owned(),
+            index: 2,
+            addrs: ips(addrs),
+        };
+        let mut flags = HashMap::new();
+        for ip in ips(addrs) {
+            if let IpAddr::V6(ip) = ip {
+                let flag = Ipv6Flags {
+                    temporary: ips(temporary).contains(&IpAddr::V6(ip)),
+                    deprecated: ips(deprecated).contains(&IpAddr::V6(ip)),
+                };
+                flags.insert(ip, flag);
+            }
+        }
+        let addrs = AddressPolicy::stable().select(&candidates(&iface, &flags));
+        Interface { addrs, ..iface }
+    }
+
+    #[test]
+    fn test_changes_deprecated() {
+        let before = advertised(&["2001:db8::1", "2001:db8::2"], &[], &[]);
+        let after = advertised(&["2001:db8::1", "2001:db8::2"], &[], &["2001:db8::1"]);
+
+        let changes = changes(&[before], &[after]);
+        let expected = Changes {
+            removed: ips(&["2001:db8::1"]),
+            added: Vec::new(),
+        };
+        assert_eq!(changes.get(&2), Some(&expected));
+    }
+
+    #[test]
+    fn test_changes_link_local_displaced() {
+        let before = advertised(&["169.254.3.4"], &[], &[]);
+        let after = advertised(&["169.254.3.4", "192.168.1.2"], &[], &[]);
+
+        let changes = changes(&[before], &[after]);
+        let expected = Changes {
+            removed: ips(&["169.254.3.4"]),
+            added: ips(&["192.168.1.2"]),
+        };
+        assert_eq!(changes.get(&2), Some(&expected));
+    }
+
+    #[test]
+    fn test_changes_filtered_temporary() {
+        let before = advertised(&["2001:db8::1", "2001:db8::abcd"], &["2001:db8::abcd"], &[]);
+        let after = advertised(&["2001:db8::1", "2001:db8::beef"], &["2001:db8::beef"], &[]);
+        assert!(changes(&[before], &[after]).is_empty());
+    }
+
+    #[test]
+    fn test_changes_interfaces() {
+        let eth0 = advertised(&["192.168.1.2"], &[], &[]);
+        let wlan0 = Interface {
+            name: "wlan0".to_owned(),
+            index: 3,
+            addrs: ips(&["10.0.0.2"]),
+        };
+
+        let changes = changes(&[eth0], &[wlan0]);
+        assert_eq!(changes[&2].removed, ips(&["192.168.1.2"]));
+        assert_eq!(changes[&3].added, ips(&["10.0.0.2"]));
+    }
+
+    #[test]
+    fn test_candidates() {
+        let iface = Interface {
+            name: "eth0".to_owned(),
+            index: 2,
+            addrs: ips(&["192.168.1.2", "2001:db8::abcd"]),
+        };
+        let mut flags = HashMap::new();
+        let temporary = Ipv6Flags {
+            temporary: true,
+            deprecated: false,
+        };
+        flags.insert("2001:db8::abcd".parse().unwrap(), temporary);
+
+        let candidates = candidates(&iface, &flags);
+        assert_eq!(
+            candidates,
+            vec![
+                addr("192.168.1.2", false, false),
+                addr("2001:db8::abcd", true, false)
+            ]
+        );
+    }
+}
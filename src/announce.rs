@@ -0,0 +1,75 @@
+//! Announcement schedule for newly established records (RFC 6762 §8.3).
+//!
+//! Once probing succeeded, the records are multicast at least twice, one
+//! second apart, with the interval doubling after each further announcement.
+//! A single lost packet then no longer leaves a service invisible until
+//! somebody queries for it.
+
+use std::time::{Duration, Instant};
+
+/// Interval between the first and the second announcement
+pub const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Announcements still to be sent for one set of records.
+#[derive(Debug)]
+pub struct Announcement {
+    remaining: u32,
+    interval: Duration,
+    deadline: Instant,
+}
+
+impl Announcement {
+    /// Schedules `count` announcements, the first of which is due right away.
+    pub fn new(count: u32) -> Announcement {
+        Announcement {
+            remaining: count,
+            interval: ANNOUNCE_INTERVAL,
+            deadline: Instant::now(),
+        }
+    }
+
+    /// Returns when the next announcement is due.
+    pub fn deadline(&self) -> Instant {
+        self.deadline
+    }
+
+    /// Returns true once every announcement went out.
+    pub fn is_finished(&self) -> bool {
+        self.remaining == 0
+    }
+
+    /// Returns true if an announcement is due at `now`, and schedules the
+    /// following one.
+    pub fn step(&mut self, now: Instant) -> bool {
+        if self.is_finished() || now < self.deadline {
+            return false;
+        }
+
+        self.remaining -= 1;
+        self.deadline = now + self.interval;
+        self.interval *= 2;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_announcement_schedule() {
+        let mut announcement = Announcement::new(3);
+        let start = announcement.deadline();
+
+        assert!(announcement.step(start));
+        assert!(!announcement.step(start));
+        assert_eq!(announcement.deadline(), start + Duration::from_secs(1));
+
+        assert!(announcement.step(start + Duration::from_secs(1)));
+        assert_eq!(announcement.deadline(), start + Duration::from_secs(3));
+
+        assert!(announcement.step(start + Duration::from_secs(3)));
+        assert!(announcement.is_finished());
+        assert!(!announcement.step(start + Duration::from_secs(60)));
+    }
+}
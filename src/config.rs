@@ -0,0 +1,278 @@
+//! Responder configuration.
+
+use crate::address_policy::AddressPolicy;
+use crate::interface::{Interface, InterfaceSelector};
+use crate::packet::{DEFAULT_MESSAGE_SIZE, MESSAGE_SIZE_LIMIT};
+
+/// Settings for a [`Responder`](struct.Responder.html).
+///
+/// # Example
+///
+/// ```no_run
+/// use mdns_responder_rs as mdns;
+///
+/// # fn main() -> std::io::Result<()> {
+/// let config = mdns::Config::new()
+///     .announcements(4)
+///     .max_message_size(8192)
+///     .deny_interface(mdns::InterfaceSelector::Glob("veth*".to_owned()));
+/// let responder = mdns::Responder::with_config(config)?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Debug)]
+pub struct Config {
+    pub(crate) announcements: u32,
+    pub(crate) max_message_size: usize,
+    pub(crate) allowed_interfaces: Vec<InterfaceSelector>,
+    pub(crate) denied_interfaces: Vec<InterfaceSelector>,
+    pub(crate) address_policy: AddressPolicy,
+    pub(crate) ttl: Ttl,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            announcements: 2,
+            max_message_size: DEFAULT_MESSAGE_SIZE,
+            allowed_interfaces: Vec::new(),
+            denied_interfaces: Vec::new(),
+            address_policy: AddressPolicy::default(),
+            ttl: Ttl::default(),
+        }
+    }
+}
+
+impl Config {
+    /// Creates a configuration with the default settings.
+    pub fn new() -> Config {
+        Config::default()
+    }
+
+    /// Sets how many times newly established records are announced.
+    ///
+    /// Announcements are sent one second apart, doubling the interval each
+    /// time. The default is 2, the minimum RFC 6762 §8.3 allows.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `count` is not between 2 and 8.
+    pub fn announcements(mut self, count: u32) -> Config {
+        assert!(
+            (2..=8).contains(&count),
+            "announcement count must be between 2 and 8, got {}",
+            count
+        );
+        self.announcements = count;
+        self
+    }
+
+    /// Sets the size in bytes of the largest message the responder sends.
+    ///
+    /// Responses that don't fit are spread over several messages. The
+    /// default of 1460 fits a standard Ethernet frame; links with jumbo
+    /// frames allow up to 9000.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `size` is not between 512 and 9000.
+    pub fn max_message_size(mut self, size: usize) -> Config {
+        assert!(
+            (512..=MESSAGE_SIZE_LIMIT).contains(&size),
+            "maximum message size must be between 512 and {}, got {}",
+            MESSAGE_SIZE_LIMIT,
+            size
+        );
+        self.max_message_size = size;
+        self
+    }
+
+    /// Restricts the responder to the interfaces `selector` picks out.
+    ///
+    /// Called several times, an interface qualifies if any of the selectors
+    /// picks it out. By default every interface qualifies. The responder
+    /// only joins the mDNS group on, answers queries from and advertises
+    /// addresses of qualifying interfaces.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `selector` is a subnet whose prefix length is longer than
+    /// its address.
+    pub fn allow_interface(mut self, selector: InterfaceSelector) -> Config {
+        assert!(selector.is_valid(), "invalid interface selector {:?}", selector);
+        self.allowed_interfaces.push(selector);
+        self
+    }
+
+    /// Keeps the responder off the interfaces `selector` picks out, even if
+    /// they are allowed by [`allow_interface`](#method.allow_interface).
+    ///
+    /// # Panics
+    ///
+    /// Panics if `selector` is a subnet whose prefix length is longer than
+    /// its address.
+    pub fn deny_interface(mut self, selector: InterfaceSelector) -> Config {
+        assert!(selector.is_valid(), "invalid interface selector {:?}", selector);
+        self.denied_interfaces.push(selector);
+        self
+    }
+
+    /// Sets which addresses of each interface go into the host's A and AAAA
+    /// records. The default is [`AddressPolicy::stable`].
+    ///
+    /// [`AddressPolicy::stable`]: struct.AddressPolicy.html#method.stable
+    pub fn address_policy(mut self, policy: AddressPolicy) -> Config {
+        self.address_policy = policy;
+        self
+    }
+
+    /// Sets the TTLs of the records the responder publishes. Services can
+    /// override them with [`Service::set_ttl`].
+    ///
+    /// [`Service::set_ttl`]: struct.Service.html#method.set_ttl
+    ///
+    /// # Panics
+    ///
+    /// Panics if any of the TTLs is 0, which would make the records goodbyes.
+    pub fn ttl(mut self, ttl: Ttl) -> Config {
+        assert!(ttl.is_valid(), "TTLs must not be 0, got {:?}", ttl);
+        self.ttl = ttl;
+        self
+    }
+
+    /// Returns whether any interfaces were allowed or denied.
+    pub(crate) fn selects_interfaces(&self) -> bool {
+        !self.allowed_interfaces.is_empty() || !self.denied_interfaces.is_empty()
+    }
+
+    /// Returns whether the responder may use `iface`.
+    pub(crate) fn uses_interface(&self, iface: &Interface) -> bool {
+        let allowed = self.allowed_interfaces.is_empty()
+            || self.allowed_interfaces.iter().any(|selector| selector.matches(iface));
+        allowed && !self.denied_interfaces.iter().any(|selector| selector.matches(iface))
+    }
+}
+
+/// Time-to-live in seconds of the records the responder publishes, by kind
+/// of record.
+///
+/// The defaults follow RFC 6762 §10: 120 seconds for records that contain
+/// the host name, 75 minutes for everything else.
+///
+/// # Example
+///
+/// ```no_run
+/// use mdns_responder_rs as mdns;
+///
+/// # fn main() -> std::io::Result<()> {
+/// let ttl = mdns::Ttl {
+///     txt: 600,
+///     ..mdns::Ttl::default()
+/// };
+/// let responder = mdns::Responder::with_config(mdns::Config::new().ttl(ttl))?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Ttl {
+    /// A and AAAA records of the host name, the reverse-mapping PTR records
+    /// of its addresses, and the NSEC records of those names.
+    pub host: u32,
+    /// PTR records listing services under their type and subtypes, and
+    /// service types under the service type enumeration name.
+    pub ptr: u32,
+    /// SRV records, and the NSEC records of service instance names along
+    /// with `txt`.
+    pub srv: u32,
+    /// TXT records.
+    pub txt: u32,
+}
+
+impl Default for Ttl {
+    fn default() -> Ttl {
+        Ttl {
+            host: 120,
+            ptr: 75 * 60,
+            srv: 120,
+            txt: 75 * 60,
+        }
+    }
+}
+
+impl Ttl {
+    /// TTL=0 for every record, which tells caches to drop them.
+    pub(crate) const GOODBYE: Ttl = Ttl {
+        host: 0,
+        ptr: 0,
+        srv: 0,
+        txt: 0,
+    };
+
+    /// Returns the TTL for the NSEC record of a service instance name, which
+    /// shouldn't outlive the SRV and TXT records it lists.
+    pub(crate) fn nsec(&self) -> u32 {
+        self.srv.min(self.txt)
+    }
+
+    /// Returns whether none of the TTLs is 0.
+    pub(crate) fn is_valid(&self) -> bool {
+        self.host > 0 && self.ptr > 0 && self.srv > 0 && self.txt > 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{IpAddr, Ipv4Addr};
+
+    fn iface(name: &str, index: u32, ip: Ipv4Addr) -> Interface {
+        Interface {
+            name: name.to_owned(),
+            index,
+            addrs: vec![IpAddr::V4(ip)],
+        }
+    }
+
+    #[test]
+    fn test_uses_interface() {
+        let eth0 = iface("eth0", 2, Ipv4Addr::new(192, 168, 1, 2));
+        let mgmt = iface("eth1", 3, Ipv4Addr::new(10, 99, 0, 2));
+        let docker = iface("docker0", 4, Ipv4Addr::new(172, 17, 0, 1));
+
+        let config = Config::new();
+        assert!(!config.selects_interfaces());
+        assert!(config.uses_interface(&docker));
+
+        let config = Config::new()
+            .allow_interface(InterfaceSelector::Glob("eth*".to_owned()))
+            .deny_interface(InterfaceSelector::Subnet(
+                IpAddr::V4(Ipv4Addr::new(10, 99, 0, 0)),
+                16,
+            ));
+        assert!(config.selects_interfaces());
+        assert!(config.uses_interface(&eth0));
+        assert!(!config.uses_interface(&mgmt));
+        assert!(!config.uses_interface(&docker));
+
+        let config = Config::new().deny_interface(InterfaceSelector::Index(4));
+        assert!(config.uses_interface(&eth0));
+        assert!(!config.uses_interface(&docker));
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_invalid_subnet() {
+        let net = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 0));
+        let _ = Config::new().allow_interface(InterfaceSelector::Subnet(net, 40));
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_zero_ttl() {
+        let ttl = Ttl {
+            ptr: 0,
+            ..Ttl::default()
+        };
+        let _ = Config::new().ttl(ttl);
+    }
+}
@@ -6,28 +6,51 @@
 use dns_parser::{self, Name, QueryClass, QueryType, RRData};
 use futures::sync::mpsc;
 use futures::{Async, Future, Poll, Stream};
-use get_if_addrs::get_if_addrs;
-use log::{debug, error, trace, warn};
-use std::collections::VecDeque;
+use log::{debug, error, info, trace, warn};
+use std::cmp::Ordering;
+use std::collections::{HashMap, VecDeque};
 use std::io;
 use std::io::ErrorKind::WouldBlock;
 use std::marker::PhantomData;
 use std::net::{IpAddr, SocketAddr};
-use tokio_core::net::UdpSocket;
-use tokio_core::reactor::Handle;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio_core::reactor::{Handle, PollEvented, Timeout};
 
-use crate::{DEFAULT_TTL, MDNS_PORT};
-use crate::address_family::AddressFamily;
-use crate::services::{ServiceData, Services};
+use crate::MDNS_PORT;
+use crate::address_family::{AddressFamily, PacketInfo};
+use crate::address_policy;
+use crate::announce::Announcement;
+use crate::config::{Config, Ttl};
+use crate::interface::{Interface, InterfaceTable};
+use crate::net;
+use crate::packet::{self, Message};
+use crate::probe::{self, Probe, ProbeStep, PROBE_DEFER};
+use crate::record::{reverse_name, RData, Record};
+use crate::responses::PendingResponses;
+use crate::services::{service_types_name, ServiceData, Services};
+use crate::stats::Counters;
+use crate::truncated::TruncatedQuery;
+use crate::watcher::Watcher;
 
-pub type AnswerBuilder = dns_parser::Builder<dns_parser::Answers>;
+/// Longest TTL given to legacy unicast queriers, which don't take part in
+/// mDNS cache maintenance (RFC 6762 §6.7)
+const LEGACY_UNICAST_TTL: u32 = 10;
+
+/// How long the interface table is relied on where no watcher tells when
+/// it changes
+const INTERFACE_TABLE_MAX_AGE: Duration = Duration::from_secs(10);
 
 /// Commands that can be sent to the FSM.
 #[derive(Clone, Debug)]
 pub enum Command {
+    Probe {
+        id: usize,
+    },
     SendUnsolicited {
         svc: ServiceData,
-        ttl: u32,
+        /// send the records with TTL=0
+        goodbye: bool,
         include_ip: bool,
     },
     Shutdown,
@@ -35,10 +58,53 @@ pub enum Command {
 
 /// The main state machine for handling mDNS operations.
 pub struct Fsm<AF: AddressFamily> {
-    socket: UdpSocket,
+    socket: net::Socket,
+    /// second handle on the socket, for options tokio's socket doesn't expose
+    options: std::net::UdpSocket,
+    /// interfaces we joined the mDNS group on, by index
+    interfaces: HashMap<u32, Interface>,
+    /// set if we joined on the system's default interface instead, as
+    /// interfaces couldn't be listed
+    default_interface: bool,
+    /// tells when interfaces or their addresses change
+    watcher: Watcher,
+    /// every interface and its addresses, whether we joined it or not,
+    /// loaded again when the watcher reports a change
+    interface_table: InterfaceTable,
     services: Services,
     commands: mpsc::UnboundedReceiver<Command>,
-    outgoing: VecDeque<(Vec<u8>, SocketAddr)>,
+    config: Config,
+    counters: Arc<Counters>,
+    /// packets to send, with the interface they go out of if it matters
+    outgoing: VecDeque<(Vec<u8>, SocketAddr, Option<u32>)>,
+    timer: Timeout,
+    /// services still probing, by id
+    probes: HashMap<usize, Probe>,
+    /// service names that finished probing and are answered, mapped to ids
+    established: HashMap<Name<'static>, usize>,
+    /// probe for the host name, while probing
+    host_probe: Option<Probe>,
+    /// host name that finished probing and is answered
+    hostname: Option<Name<'static>>,
+    /// host name last announced, which gets goodbyes once another one is
+    /// established in its place
+    announced_hostname: Option<Name<'static>>,
+    /// pending announcements for established services, by id
+    announcements: HashMap<usize, Announcement>,
+    /// pending announcements for the host name
+    host_announcement: Option<Announcement>,
+    /// interfaces that came up after our names were established, where all
+    /// of them are probed for before being answered, by index
+    link_probes: HashMap<u32, Probe>,
+    /// pending announcements of all our names on interfaces whose probing
+    /// finished, by index
+    link_announcements: HashMap<u32, Announcement>,
+    /// multicast answers not sent yet, by the interface they go out of
+    responses: HashMap<Option<u32>, PendingResponses>,
+    /// truncated queries waiting for more known answers, by source
+    truncated: HashMap<SocketAddr, TruncatedQuery>,
+    /// set once goodbyes went out; the future completes when they're sent
+    shutting_down: bool,
     _af: PhantomData<AF>,
 }
 
@@ -47,27 +113,257 @@ impl<AF: AddressFamily> Fsm<AF> {
     pub fn new(
         handle: &Handle,
         services: &Services,
+        config: &Config,
+        counters: &Arc<Counters>,
     ) -> io::Result<(Fsm<AF>, mpsc::UnboundedSender<Command>)> {
         let std_socket = AF::bind()?;
-        let socket = UdpSocket::from_socket(std_socket, handle)?;
+        net::set_pktinfo(&std_socket, AF::v6())?;
+        let (interfaces, default_interface) = Self::join_interfaces(&std_socket, config)?;
+        let options = std_socket.try_clone()?;
+        let socket = PollEvented::new(mio::net::UdpSocket::from_socket(std_socket)?, handle)?;
         let (tx, rx) = mpsc::unbounded();
 
         let fsm = Fsm {
             socket,
+            options,
+            interfaces,
+            default_interface,
+            watcher: Watcher::new(handle),
+            interface_table: InterfaceTable::load(),
             services: services.clone(),
             commands: rx,
+            config: config.clone(),
+            counters: counters.clone(),
             outgoing: VecDeque::new(),
+            timer: Timeout::new_at(Instant::now(), handle)?,
+            probes: HashMap::new(),
+            established: HashMap::new(),
+            host_probe: None,
+            hostname: None,
+            announced_hostname: None,
+            announcements: HashMap::new(),
+            host_announcement: None,
+            link_probes: HashMap::new(),
+            link_announcements: HashMap::new(),
+            responses: HashMap::new(),
+            truncated: HashMap::new(),
+            shutting_down: false,
             _af: PhantomData,
         };
 
         Ok((fsm, tx))
     }
 
+    /// Joins the mDNS group on every multicast-capable interface the
+    /// configuration allows. Returns the interfaces joined, and whether the
+    /// system's default interface was joined instead.
+    ///
+    /// The default interface is the fallback where interfaces can't be
+    /// listed, or none could be joined without any being configured.
+    fn join_interfaces(
+        socket: &std::net::UdpSocket,
+        config: &Config,
+    ) -> io::Result<(HashMap<u32, Interface>, bool)> {
+        let found = match net::interfaces() {
+            Ok(found) => found,
+            Err(err) => {
+                error!("could not get list of interfaces: {err}");
+                Vec::new()
+            }
+        };
+        let listed = !found.is_empty();
+
+        let mut interfaces = HashMap::new();
+        for iface in found {
+            if !config.uses_interface(&iface) {
+                debug!("not using {}", iface.name);
+                continue;
+            }
+            match AF::join_multicast(socket, Some(&iface)) {
+                Ok(()) => {
+                    debug!("joined mDNS group on {}", iface.name);
+                    interfaces.insert(iface.index, iface);
+                }
+                Err(err) => debug!("could not join mDNS group on {}: {err}", iface.name),
+            }
+        }
+
+        if interfaces.is_empty() && (!listed || !config.selects_interfaces()) {
+            if config.selects_interfaces() {
+                warn!("interfaces can't be listed, so they can't be selected either");
+            }
+            debug!("joining mDNS group on the default interface");
+            AF::join_multicast(socket, None)?;
+            return Ok((interfaces, true));
+        }
+        Ok((interfaces, false))
+    }
+
+    /// Compares the interfaces with the ones we joined the mDNS group on,
+    /// after the watcher reported a change.
+    ///
+    /// Interfaces that went away or down are left. New interfaces, or ones
+    /// that came up, are joined, and our established names are probed for
+    /// and announced there since they are new on that link (RFC 6762 §8),
+    /// while the other interfaces keep being answered.
+    ///
+    /// Addresses are compared as the address policy picks them, so that an
+    /// address the policy stops advertising, such as one that got deprecated,
+    /// gets goodbyes on its interface, while changes to addresses the policy
+    /// leaves out don't cause announcements.
+    fn refresh_interfaces(&mut self) {
+        let before = self.host_interfaces(None);
+        self.interface_table = InterfaceTable::load();
+        let found = match net::interfaces() {
+            Ok(found) => found,
+            Err(err) => {
+                error!("could not get list of interfaces: {err}");
+                return;
+            }
+        };
+        let found: HashMap<u32, Interface> = found
+            .into_iter()
+            .filter(|iface| self.config.uses_interface(iface))
+            .map(|iface| (iface.index, iface))
+            .collect();
+
+        let gone: Vec<u32> = self
+            .interfaces
+            .keys()
+            .filter(|index| !found.contains_key(index))
+            .cloned()
+            .collect();
+        for index in gone {
+            if let Some(iface) = self.interfaces.remove(&index) {
+                info!("leaving mDNS group on {}", iface.name);
+                if let Err(err) = AF::leave_multicast(&self.options, &iface) {
+                    // the membership went away with the interface
+                    debug!("could not leave mDNS group on {}: {err}", iface.name);
+                }
+                self.responses.remove(&Some(index));
+                self.link_probes.remove(&index);
+                self.link_announcements.remove(&index);
+            }
+        }
+
+        let mut joined = Vec::new();
+        for (index, iface) in found {
+            if let Some(old) = self.interfaces.get_mut(&index) {
+                *old = iface;
+                continue;
+            }
+            match AF::join_multicast(&self.options, Some(&iface)) {
+                Ok(()) => {
+                    info!("joined mDNS group on {}", iface.name);
+                    self.interfaces.insert(index, iface);
+                    self.probe_link(index);
+                    joined.push(index);
+                }
+                Err(err) => debug!("could not join mDNS group on {}: {err}", iface.name),
+            }
+        }
+
+        // newly joined interfaces get all our records announced once probed
+        let after = self.host_interfaces(None);
+        let mut added = false;
+        for (index, change) in address_policy::changes(&before, &after) {
+            if joined.contains(&index) {
+                continue;
+            }
+            if self.interfaces.contains_key(&index) {
+                self.send_address_goodbyes(index, &change.removed);
+            }
+            added |= change.added.iter().any(|&ip| address_data::<AF>(ip).is_some());
+        }
+
+        if added && self.hostname.is_some() {
+            debug!("announcing new addresses");
+            self.host_announcement = Some(Announcement::new(self.config.announcements));
+        }
+    }
+
+    /// Starts probing for our established names on the newly joined
+    /// interface with index `index`. Names still probing are probed for on
+    /// every interface anyway.
+    fn probe_link(&mut self, index: u32) {
+        if self.hostname.is_none() && self.established.is_empty() {
+            return;
+        }
+        let hostname = match self.services.read() {
+            Ok(services) => services.get_hostname().clone(),
+            Err(e) => {
+                error!("Failed to acquire services lock: {e:?}");
+                return;
+            }
+        };
+
+        debug!("probing for our names on interface {index}");
+        // the probe's name only shows up in logs, it stands for all our names
+        self.link_probes.insert(index, Probe::new(hostname));
+        self.link_announcements.remove(&index);
+    }
+
+    /// Sends TTL=0 goodbyes out of the interface with index `index` for the
+    /// addresses removed from it, along with their reverse-mapping PTR
+    /// records.
+    fn send_address_goodbyes(&mut self, index: u32, removed: &[IpAddr]) {
+        let hostname = match self.hostname {
+            Some(ref hostname) => hostname.clone(),
+            None => return,
+        };
+
+        let mut message = Message::response(0);
+        for &ip in removed {
+            if let Some(data) = address_data::<AF>(ip) {
+                message.answers.push(Record::unique(hostname.clone(), 0, data));
+                let ptr = RData::PTR(hostname.clone());
+                message.answers.push(Record::unique(reverse_name(ip), 0, ptr));
+            }
+        }
+        if message.is_empty() {
+            return;
+        }
+
+        if let Some(responses) = self.responses.get_mut(&Some(index)) {
+            for record in &message.answers {
+                responses.cancel(record);
+            }
+        }
+
+        debug!("sending goodbyes for {} removed addresses", message.answers.len() / 2);
+        let addr = SocketAddr::new(AF::mdns_group(), MDNS_PORT);
+        self.send_message(message, addr, Some(index));
+    }
+
+    /// Returns the interfaces multicasts go out of, where `None` stands for
+    /// the system's default interface.
+    fn multicast_interfaces(&self) -> Vec<Option<u32>> {
+        if self.interfaces.is_empty() && self.default_interface {
+            return vec![None];
+        }
+        self.interfaces.keys().map(|&index| Some(index)).collect()
+    }
+
+    /// Returns whether packets arriving on the interface with index
+    /// `ifindex` are handled, as the configuration allows that interface.
+    fn accepts(&self, ifindex: u32) -> bool {
+        if !self.config.selects_interfaces() || self.interfaces.contains_key(&ifindex) {
+            return true;
+        }
+
+        // queries sent straight to us may come in on an interface we didn't
+        // join, such as one that can't multicast
+        self.interface_table
+            .interfaces
+            .iter()
+            .any(|iface| iface.index == ifindex && self.config.uses_interface(iface))
+    }
+
     fn recv_packets(&mut self) -> io::Result<()> {
-        let mut buf = [0u8; 4096];
+        let mut buf = [0u8; packet::MESSAGE_SIZE_LIMIT];
         loop {
-            let (bytes, addr) = match self.socket.recv_from(&mut buf) {
-                Ok((bytes, addr)) => (bytes, addr),
+            let (bytes, addr, info) = match net::recv_from(&self.socket, &mut buf) {
+                Ok(received) => received,
                 Err(ref ioerr) if ioerr.kind() == WouldBlock => break,
                 Err(err) => return Err(err),
             };
@@ -77,14 +373,19 @@ impl<AF: AddressFamily> Fsm<AF> {
                 continue;
             }
 
-            self.handle_packet(&buf[..bytes], addr);
+            self.handle_packet(&buf[..bytes], addr, info);
         }
         Ok(())
     }
 
-    fn handle_packet(&mut self, buffer: &[u8], addr: SocketAddr) {
+    fn handle_packet(&mut self, buffer: &[u8], addr: SocketAddr, info: Option<PacketInfo>) {
         trace!("received packet from {addr:?}");
 
+        if let Some(info) = info.filter(|info| !self.accepts(info.ifindex)) {
+            trace!("ignoring packet from {addr:?} on interface {}", info.ifindex);
+            return;
+        }
+
         let packet = match dns_parser::Packet::parse(buffer) {
             Ok(packet) => packet,
             Err(error) => {
@@ -94,127 +395,927 @@ impl<AF: AddressFamily> Fsm<AF> {
         };
 
         if !packet.header.query {
-            trace!("received packet from {addr:?} with no query");
+            self.suppress_duplicates(&packet, addr, info.map(|info| info.ifindex));
+            self.handle_response(&packet, addr);
+            return;
+        }
+
+        if !packet.nameservers.is_empty() {
+            self.handle_probe(&packet, addr, info.map(|info| info.ifindex));
+        }
+
+        let known_answers: Vec<Record> = packet.answers.iter().filter_map(Record::from_rr).collect();
+
+        // known answers continuing a truncated query from the same source
+        if packet.questions.is_empty() {
+            let full = match self.truncated.get_mut(&addr) {
+                Some(query) => {
+                    trace!("{} more known answers from {addr:?}", known_answers.len());
+                    query.add_known_answers(known_answers, packet.header.truncated)
+                }
+                None => false,
+            };
+            if full {
+                debug!("no room for more known answers from {addr:?}, answering");
+                if let Some(query) = self.truncated.remove(&addr) {
+                    self.answer_truncated(query, addr);
+                }
+            }
             return;
         }
 
         if packet.header.truncated {
-            warn!("dropping truncated packet from {addr:?}");
+            debug!("waiting for the rest of the known answers from {addr:?}");
+            if let Some(query) = self.truncated.remove(&addr) {
+                self.answer_truncated(query, addr);
+            }
+            self.truncated.insert(addr, TruncatedQuery::new(buffer, info, known_answers));
+            return;
+        }
+
+        self.answer_query(&packet, addr, info, &known_answers);
+    }
+
+    /// Compares the records another host proposes in a probe for a name we
+    /// are probing for ourselves with the records we intend to use, and
+    /// defers our probing if we lose the tiebreak (RFC 6762 §8.2).
+    ///
+    /// For the host name, our records are the addresses of the interface
+    /// with index `ifindex` the probe arrived on, if known.
+    fn handle_probe(
+        &mut self,
+        packet: &dns_parser::Packet,
+        addr: SocketAddr,
+        ifindex: Option<u32>,
+    ) {
+        let services = match self.services.read() {
+            Ok(s) => s,
+            Err(e) => {
+                error!("Failed to acquire services lock: {e:?}");
+                return;
+            }
+        };
+
+        let host_lost = self.host_probe.as_ref().filter(|probe| {
+            let theirs = probe::proposed_records(packet, probe.name());
+            let ours = self.ip_records(probe.name(), self.config.ttl.host, ifindex);
+            !theirs.is_empty() && probe::tiebreak(&ours, &theirs) == Ordering::Less
+        });
+        if let Some(name) = host_lost.map(|probe| probe.name().clone()) {
+            debug!("lost probe tiebreak for host name {name} against {addr:?}");
+            self.host_probe = Some(Probe::deferred(name, PROBE_DEFER));
+        }
+
+        let mut lost = Vec::new();
+        for (&id, probe) in &self.probes {
+            let theirs = probe::proposed_records(packet, probe.name());
+            let svc = match services.find_by_id(id) {
+                Some(svc) if !theirs.is_empty() => svc,
+                _ => continue,
+            };
+            let ttl = self.service_ttl(svc);
+            let ours = [
+                svc.srv_record(services.get_hostname(), ttl.srv),
+                svc.txt_record(ttl.txt),
+            ];
+
+            if probe::tiebreak(&ours, &theirs) == Ordering::Less {
+                lost.push((id, probe.name().clone()));
+            }
+        }
+
+        for (id, name) in lost {
+            debug!("lost probe tiebreak for {name} against {addr:?}");
+            self.probes.insert(id, Probe::deferred(name, PROBE_DEFER));
+        }
+    }
+
+    /// Answers a truncated query once its follow-up packets had time to
+    /// arrive.
+    fn answer_truncated(&mut self, query: TruncatedQuery, addr: SocketAddr) {
+        let (buffer, info, known_answers) = query.into_parts();
+        match dns_parser::Packet::parse(&buffer) {
+            Ok(packet) => self.answer_query(&packet, addr, info, &known_answers),
+            Err(error) => warn!("couldn't parse packet from {addr:?}: {error}"),
+        }
+    }
+
+    /// Answers the questions of a query that arrived as described by `info`,
+    /// leaving out `known_answers`.
+    ///
+    /// Queries from a port other than 5353 come from legacy resolvers
+    /// (RFC 6762 §6.7). They get a plain unicast DNS response, while queries
+    /// sent straight to our address get a unicast mDNS response.
+    ///
+    /// When the platform reports the interface the query arrived on, the
+    /// response only carries that interface's addresses and goes out of it,
+    /// so the querier isn't handed addresses it has no route to.
+    fn answer_query(
+        &mut self,
+        packet: &dns_parser::Packet,
+        addr: SocketAddr,
+        info: Option<PacketInfo>,
+        known_answers: &[Record],
+    ) {
+        let legacy = addr.port() != MDNS_PORT;
+        let direct = info.is_some_and(|info| !info.dst.is_multicast());
+        let ifindex = info.map(|info| info.ifindex);
+
+        if let Some(index) = ifindex.filter(|index| self.link_probes.contains_key(index)) {
+            trace!("not answering {addr:?} on interface {index} before probing there");
             return;
         }
 
-        let mut unicast_builder = dns_parser::Builder::new_response(packet.header.id, false)
-            .move_to::<dns_parser::Answers>();
-        let mut multicast_builder = dns_parser::Builder::new_response(packet.header.id, false)
-            .move_to::<dns_parser::Answers>();
-        unicast_builder.set_max_size(None);
-        multicast_builder.set_max_size(None);
+        let mut unicast = Message::response(packet.header.id);
+        let mut multicast = Message::response(0);
 
-        for question in packet.questions {
+        for question in &packet.questions {
             debug!(
                 "received question: {:?} {}",
                 question.qclass, question.qname
             );
 
+            if legacy {
+                if let Ok(name) = Name::from_str(question.qname.to_string()) {
+                    unicast.questions.push(packet::Question {
+                        name,
+                        qtype: question.qtype as u16,
+                        unicast: false,
+                    });
+                }
+            }
+
             if question.qclass == QueryClass::IN || question.qclass == QueryClass::Any {
-                if question.qu {
-                    unicast_builder = self.handle_question(&question, unicast_builder);
+                if question.qu || legacy || direct {
+                    self.handle_question(question, known_answers, ifindex, &mut unicast);
                 } else {
-                    multicast_builder = self.handle_question(&question, multicast_builder);
+                    self.handle_question(question, known_answers, ifindex, &mut multicast);
                 }
             }
         }
 
-        if !multicast_builder.is_empty() {
-            let response = multicast_builder.build().unwrap_or_else(|x| x);
-            let addr = SocketAddr::new(AF::mdns_group(), MDNS_PORT);
-            self.outgoing.push_back((response, addr));
+        unicast.dedup_additional();
+        multicast.dedup_additional();
+
+        if legacy {
+            unicast.cache_flush = false;
+            let records = unicast.answers.iter_mut().chain(unicast.additional.iter_mut());
+            for record in records {
+                record.ttl = record.ttl.min(LEGACY_UNICAST_TTL);
+            }
         }
 
-        if !unicast_builder.is_empty() {
-            let response = unicast_builder.build().unwrap_or_else(|x| x);
-            self.outgoing.push_back((response, addr));
+        // multicast answers go out once their response delay has passed,
+        // additional records along with the last of them; defending our names
+        // against a probe is exempt from rate limiting
+        let now = Instant::now();
+        let probe = !packet.nameservers.is_empty();
+        let responses = self.responses.entry(ifindex).or_default();
+        let counters = &self.counters;
+        let mut sendable = |record: &Record| {
+            let limited = !probe && responses.rate_limited(record, now);
+            if limited {
+                trace!("{} {:?} was multicast less than a second ago", record.name, record.data);
+                counters.add_rate_limited();
+            }
+            !limited
+        };
+        let answers: Vec<Record> = multicast.answers.into_iter().filter(&mut sendable).collect();
+        let additional: Vec<Record> =
+            multicast.additional.into_iter().filter(&mut sendable).collect();
+
+        let deadline = answers.into_iter().map(|record| responses.schedule(record, now)).max();
+        match deadline {
+            Some(deadline) => {
+                for record in additional {
+                    responses.schedule_additional(record, deadline, now);
+                }
+            }
+            None => trace!("no answers left to send the additional records with"),
+        }
+
+        // a legacy response echoes the questions, so check for answers
+        if !unicast.answers.is_empty() {
+            self.send_message(unicast, addr, ifindex);
+        }
+    }
+
+    /// Cancels pending multicast answers that another host just sent with at
+    /// least the TTL we would have given (RFC 6762 §7.4). Only answers going
+    /// out of the interface the response arrived on count as sent.
+    fn suppress_duplicates(
+        &mut self,
+        packet: &dns_parser::Packet,
+        addr: SocketAddr,
+        ifindex: Option<u32>,
+    ) {
+        let responses = match self.responses.get_mut(&ifindex) {
+            Some(responses) => responses,
+            None => return,
+        };
+        for record in packet.answers.iter().filter_map(Record::from_rr) {
+            if responses.suppress(&record) {
+                trace!("{addr:?} already answered {} {:?}", record.name, record.data);
+                self.counters.add_duplicate_answer();
+            }
         }
     }
 
+    /// Adds the records answering `question` to `response`, leaving out
+    /// those the querier listed as known answers.
+    ///
+    /// Only the records asked for go among the answers. The ones the querier
+    /// will need next, like the SRV and TXT records and addresses behind a
+    /// PTR record, go in the additional section (RFC 6763 §12).
+    ///
+    /// Asking for a type we don't have on one of our unique names gets an
+    /// NSEC record listing the types we do have (RFC 6762 §6.1). Positive
+    /// answers carry it in the additional section.
+    ///
+    /// An ANY question gets every record we own under the name, which is
+    /// also how we defend our names against other hosts' probes.
+    ///
+    /// Address records are limited to the interface with index `ifindex`, if
+    /// given.
     fn handle_question(
         &self,
         question: &dns_parser::Question,
-        mut builder: AnswerBuilder,
-    ) -> AnswerBuilder {
+        known_answers: &[Record],
+        ifindex: Option<u32>,
+        response: &mut Message,
+    ) {
         let services = match self.services.read() {
             Ok(s) => s,
             Err(e) => {
                 error!("Failed to acquire services lock: {e:?}");
-                return builder;
+                return;
             }
         };
+        let hostname = services.get_hostname();
+        let host_ttl = self.config.ttl.host;
+        let qtype = question.qtype as u16;
+        let mut answers = Vec::new();
+        let mut additional = Vec::new();
+
+        if question.qname == *hostname && self.hostname.as_ref() == Some(hostname) {
+            let types = self.host_rtypes(ifindex);
+            let records: Vec<Record> = self
+                .ip_records(hostname, host_ttl, ifindex)
+                .into_iter()
+                .filter(|record| qtype == packet::TYPE_ANY || record.data.rtype() == qtype)
+                .collect();
+            let has_type = qtype == packet::TYPE_ANY || types.contains(&qtype);
+            let nsec = Record::nsec(hostname.clone(), host_ttl, types);
+
+            if !records.is_empty() {
+                answers.extend(records);
+                additional.push(nsec);
+            } else if !has_type {
+                answers.push(nsec);
+            }
+        }
 
-        match question.qtype {
-            QueryType::A | QueryType::AAAA | QueryType::All
-                if question.qname == *services.get_hostname() =>
-            {
-                builder = self.add_ip_rr(services.get_hostname(), builder, DEFAULT_TTL);
-            }
-            QueryType::PTR => {
-                for svc in services.find_by_type(&question.qname) {
-                    builder = svc.add_ptr_rr(builder, DEFAULT_TTL);
-                    builder = svc.add_srv_rr(services.get_hostname(), builder, DEFAULT_TTL);
-                    builder = svc.add_txt_rr(builder, DEFAULT_TTL);
-                    builder = self.add_ip_rr(services.get_hostname(), builder, DEFAULT_TTL);
+        let reverse = question.qname.to_string().ends_with(".arpa")
+            && self.hostname.as_ref() == Some(hostname);
+        if reverse {
+            let found = self
+                .reverse_records(hostname, host_ttl, ifindex)
+                .into_iter()
+                .find(|record| question.qname == record.name);
+            if let Some(ptr) = found {
+                let nsec = Record::nsec(ptr.name.clone(), host_ttl, vec![ptr.data.rtype()]);
+                match question.qtype {
+                    QueryType::PTR | QueryType::All => {
+                        answers.push(ptr);
+                        additional.push(nsec);
+                    }
+                    _ => answers.push(nsec),
                 }
             }
-            QueryType::SRV => {
-                if let Some(svc) = services.find_by_name(&question.qname) {
-                    builder = svc.add_srv_rr(services.get_hostname(), builder, DEFAULT_TTL);
-                    builder = self.add_ip_rr(services.get_hostname(), builder, DEFAULT_TTL);
+        }
+
+        let instance = services
+            .find_by_name(&question.qname)
+            .filter(|svc| self.established.contains_key(&svc.name));
+        if let Some(svc) = instance {
+            let ttl = self.service_ttl(svc);
+            let nsec = svc.nsec_record(hostname, ttl.nsec());
+            match question.qtype {
+                QueryType::SRV => {
+                    answers.push(svc.srv_record(hostname, ttl.srv));
+                    additional.push(nsec);
+                    self.add_ip_rr(hostname, &mut additional, host_ttl, ifindex);
                 }
+                QueryType::TXT => {
+                    answers.push(svc.txt_record(ttl.txt));
+                    additional.push(nsec);
+                }
+                QueryType::All => {
+                    answers.push(svc.srv_record(hostname, ttl.srv));
+                    answers.push(svc.txt_record(ttl.txt));
+                    additional.push(nsec);
+                    self.add_ip_rr(hostname, &mut additional, host_ttl, ifindex);
+                }
+                _ => answers.push(nsec),
             }
-            QueryType::TXT => {
-                if let Some(svc) = services.find_by_name(&question.qname) {
-                    builder = svc.add_txt_rr(builder, DEFAULT_TTL);
+        }
+
+        // type, subtype and service type enumeration names only have PTRs
+        let ptr_question = question.qtype == QueryType::PTR || question.qtype == QueryType::All;
+
+        if ptr_question && question.qname == service_types_name() {
+            for typ in services.types() {
+                let found = services
+                    .find_by_type(typ)
+                    .find(|svc| self.established.contains_key(&svc.name));
+                if let Some(svc) = found {
+                    answers.push(svc.type_ptr_record(self.service_ttl(svc).ptr));
                 }
             }
-            _ => (),
         }
 
-        builder
+        if ptr_question {
+            let found = services
+                .find_by_type(&question.qname)
+                .filter(|svc| self.established.contains_key(&svc.name));
+            for svc in found {
+                let ttl = self.service_ttl(svc);
+                answers.push(svc.ptr_record(ttl.ptr));
+                additional.push(svc.srv_record(hostname, ttl.srv));
+                additional.push(svc.txt_record(ttl.txt));
+            }
+
+            let found = services
+                .find_by_subtype(&question.qname)
+                .filter(|svc| self.established.contains_key(&svc.name));
+            for svc in found {
+                let ttl = self.service_ttl(svc);
+                let ptr = svc
+                    .subtype_ptr_records(ttl.ptr)
+                    .into_iter()
+                    .filter(|record| question.qname == record.name);
+                answers.extend(ptr);
+                additional.push(svc.srv_record(hostname, ttl.srv));
+                additional.push(svc.txt_record(ttl.txt));
+            }
+
+            // addresses go with the SRV records of the services found
+            if !additional.is_empty() {
+                self.add_ip_rr(hostname, &mut additional, host_ttl, ifindex);
+            }
+        }
+
+        let count = answers.len() + additional.len();
+        answers.retain(|record| !record.is_known(known_answers));
+        additional.retain(|record| !record.is_known(known_answers));
+        let known = count - answers.len() - additional.len();
+        if known > 0 {
+            trace!("suppressed {known} known answers for {}", question.qname);
+            self.counters.add_known_answers(known);
+        }
+
+        // additional records are pointless without the answers they go with
+        if answers.is_empty() {
+            return;
+        }
+        response.answers.extend(answers);
+        response.additional.extend(additional);
     }
 
-    fn add_ip_rr(&self, hostname: &Name, mut builder: AnswerBuilder, ttl: u32) -> AnswerBuilder {
-        let interfaces = match get_if_addrs() {
-            Ok(interfaces) => interfaces,
-            Err(err) => {
-                error!("could not get list of interfaces: {err}");
-                return builder;
+    /// Adds our address records to `answers`, once the host name finished
+    /// probing.
+    fn add_ip_rr(
+        &self,
+        hostname: &Name<'static>,
+        answers: &mut Vec<Record>,
+        ttl: u32,
+        ifindex: Option<u32>,
+    ) {
+        if self.hostname.as_ref() == Some(hostname) {
+            answers.extend(self.ip_records(hostname, ttl, ifindex));
+        }
+    }
+
+    /// Returns the TTLs for the records of `svc`: its own if it set any, the
+    /// configured ones otherwise.
+    fn service_ttl(&self, svc: &ServiceData) -> Ttl {
+        svc.ttl.unwrap_or(self.config.ttl)
+    }
+
+    /// Returns the interfaces whose addresses we advertise: those the
+    /// configuration allows, or only the one with index `ifindex` if given.
+    /// Each comes with only the addresses the address policy picks.
+    fn host_interfaces(&self, ifindex: Option<u32>) -> Vec<Interface> {
+        let flags = &self.interface_table.ipv6_flags;
+        self.interface_table
+            .interfaces
+            .iter()
+            .filter(|iface| ifindex.is_none_or(|ifindex| iface.index == ifindex))
+            .filter(|iface| self.config.uses_interface(iface))
+            .map(|iface| {
+                let candidates = address_policy::candidates(iface, flags);
+                Interface {
+                    addrs: self.config.address_policy.select(&candidates),
+                    ..iface.clone()
+                }
+            })
+            .collect()
+    }
+
+    /// Returns an address record for every address of this address family
+    /// on the interfaces [`host_interfaces`](#method.host_interfaces)
+    /// returns.
+    fn ip_records(&self, hostname: &Name<'static>, ttl: u32, ifindex: Option<u32>) -> Vec<Record> {
+        let mut records = Vec::new();
+        for iface in self.host_interfaces(ifindex) {
+            trace!("found interface {iface:?}");
+            for &ip in &iface.addrs {
+                if let Some(data) = address_data::<AF>(ip) {
+                    records.push(Record::unique(hostname.clone(), ttl, data));
+                }
+            }
+        }
+
+        records
+    }
+
+    /// Returns the address record types we have, for the NSEC record of our
+    /// host name. Unlike the records themselves, this covers both address
+    /// families.
+    fn host_rtypes(&self, ifindex: Option<u32>) -> Vec<u16> {
+        self.host_interfaces(ifindex)
+            .iter()
+            .flat_map(|iface| iface.addrs.iter())
+            .map(|ip| match *ip {
+                IpAddr::V4(ip) => RData::A(ip).rtype(),
+                IpAddr::V6(ip) => RData::AAAA(ip).rtype(),
+            })
+            .collect()
+    }
+
+    /// Returns a reverse-mapping PTR record pointing to `hostname` for each
+    /// address [`ip_records`](#method.ip_records) returns.
+    fn reverse_records(
+        &self,
+        hostname: &Name<'static>,
+        ttl: u32,
+        ifindex: Option<u32>,
+    ) -> Vec<Record> {
+        self.ip_records(hostname, ttl, ifindex)
+            .into_iter()
+            .filter_map(|record| {
+                let ip = match record.data {
+                    RData::A(ip) => IpAddr::V4(ip),
+                    RData::AAAA(ip) => IpAddr::V6(ip),
+                    _ => return None,
+                };
+                let data = RData::PTR(hostname.clone());
+                Some(Record::unique(reverse_name(ip), ttl, data))
+            })
+            .collect()
+    }
+
+    /// Checks a response from another host for records claiming one of our
+    /// names.
+    ///
+    /// A conflict while probing renames the service and starts over with the
+    /// new name. A conflicting record for an established service puts it back
+    /// into probing, which renames it if the other host still answers.
+    fn handle_response(&mut self, packet: &dns_parser::Packet, addr: SocketAddr) {
+        let mut services = match self.services.write() {
+            Ok(s) => s,
+            Err(e) => {
+                error!("Failed to acquire services lock: {e:?}");
+                return;
             }
         };
 
-        for iface in interfaces {
-            if iface.is_loopback() {
-                continue;
+        for rr in packet.answers.iter().chain(packet.additional.iter()) {
+            if let Some(probe) = self.host_probe.as_ref().filter(|probe| rr.name == *probe.name()) {
+                let name = if services.get_hostname() == probe.name() {
+                    services.rename_host()
+                } else {
+                    services.get_hostname().clone()
+                };
+                info!("host name {} is already in use (reported by {addr:?}), renamed to {name}", rr.name);
+                self.host_probe = Some(Probe::new(name));
             }
 
-            trace!("found interface {iface:?}");
-            match iface.ip() {
-                IpAddr::V4(ip) if !AF::v6() => {
-                    builder = builder.add_answer(hostname, QueryClass::IN, ttl, &RRData::A(ip))
+            let host_conflict = self.hostname.as_ref().is_some_and(|name| rr.name == *name)
+                && match rr.data {
+                    RRData::A(ip) => !self.interface_table.has_address(IpAddr::V4(ip)),
+                    RRData::AAAA(ip) => !self.interface_table.has_address(IpAddr::V6(ip)),
+                    _ => false,
+                };
+            if host_conflict {
+                warn!("conflicting address for {} from {addr:?}, probing again", rr.name);
+                self.host_probe = self.hostname.take().map(Probe::new);
+            }
+
+            let probing: Vec<usize> = self
+                .probes
+                .iter()
+                .filter(|(_, probe)| rr.name == *probe.name())
+                .map(|(&id, _)| id)
+                .collect();
+
+            for id in probing {
+                let current = services.find_by_id(id).map(|svc| svc.name.clone());
+                let name = match current {
+                    Some(ref name) if rr.name == *name => services.rename(id),
+                    // already renamed by the responder of the other family
+                    current => current,
+                };
+
+                match name {
+                    Some(name) => {
+                        info!("{} is already in use (reported by {addr:?}), renamed to {name}", rr.name);
+                        self.probes.insert(id, Probe::new(name));
+                    }
+                    None => {
+                        let name = &rr.name;
+                        error!("{name} is already in use (reported by {addr:?}), giving up on it");
+                        self.probes.remove(&id);
+                    }
+                }
+            }
+
+            let id = match self.established.get(&rr.name) {
+                Some(&id) => id,
+                None => continue,
+            };
+            let (svc, theirs) = match (services.find_by_id(id), Record::from_rr(rr)) {
+                (Some(svc), Some(theirs)) => (svc, theirs),
+                _ => continue,
+            };
+
+            let ttl = self.service_ttl(svc);
+            let ours = [
+                svc.srv_record(services.get_hostname(), ttl.srv),
+                svc.txt_record(ttl.txt),
+            ];
+            let same_type = ours.iter().any(|record| record.data.rtype() == theirs.data.rtype());
+            let same_data = ours.iter().any(|record| record.data == theirs.data);
+
+            if same_type && !same_data {
+                warn!("conflicting record for {} from {addr:?}, probing again", svc.name);
+                let name = svc.name.clone();
+                self.established.remove(&name);
+                self.probes.insert(id, Probe::new(name));
+            }
+        }
+    }
+
+    /// Catches up with renames done by the responder of the other address
+    /// family: names that are no longer registered are forgotten, and names
+    /// that are neither probing nor established here are probed.
+    ///
+    /// This also starts probing for the host name when the responder starts.
+    fn sync_services(&mut self) {
+        let services = match self.services.read() {
+            Ok(s) => s,
+            Err(e) => {
+                error!("Failed to acquire services lock: {e:?}");
+                return;
+            }
+        };
+
+        let hostname = services.get_hostname();
+        let host_probing = self.host_probe.as_ref().map(Probe::name) == Some(hostname);
+        if self.hostname.as_ref() != Some(hostname) && !host_probing {
+            debug!("probing for host name {hostname}");
+            self.hostname = None;
+            self.host_probe = Some(Probe::new(hostname.clone()));
+        }
+
+        // Services that are gone entirely keep their entry until their
+        // goodbye command arrives.
+        self.established.retain(|name, id| match services.find_by_id(*id) {
+            Some(svc) => svc.name == *name,
+            None => true,
+        });
+
+        for (id, svc) in services.iter() {
+            if !self.probes.contains_key(&id) && self.established.get(&svc.name) != Some(&id) {
+                debug!("{} was renamed, probing for it", svc.name);
+                self.probes.insert(id, Probe::new(svc.name.clone()));
+            }
+        }
+    }
+
+    /// Starts probing for the name of a newly registered service.
+    fn start_probe(&mut self, id: usize) {
+        let name = match self.services.read() {
+            Ok(services) => match services.find_by_id(id) {
+                Some(svc) => svc.name.clone(),
+                None => return,
+            },
+            Err(e) => {
+                error!("Failed to acquire services lock: {e:?}");
+                return;
+            }
+        };
+
+        self.probes.insert(id, Probe::new(name));
+    }
+
+    /// Sends the probes and announcements that are due, and schedules
+    /// announcements for names whose probing finished without conflict.
+    fn run_timers(&mut self) {
+        self.sync_services();
+
+        let now = Instant::now();
+        let mut due = Vec::new();
+        let mut finished = Vec::new();
+
+        for (&id, probe) in self.probes.iter_mut() {
+            match probe.step(now) {
+                Some(ProbeStep::Send) => due.push(id),
+                Some(ProbeStep::Done) => finished.push(id),
+                None => (),
+            }
+        }
+
+        let host_step = self.host_probe.as_mut().and_then(|probe| probe.step(now));
+        let probe_host = host_step == Some(ProbeStep::Send);
+
+        if !due.is_empty() || probe_host {
+            let host = match self.host_probe {
+                Some(ref probe) if probe_host => Some(probe.name().clone()),
+                _ => None,
+            };
+            self.send_probes(&due, host.as_ref(), &self.multicast_interfaces());
+        }
+
+        if host_step == Some(ProbeStep::Done) {
+            if let Some(probe) = self.host_probe.take() {
+                info!("probing for host name {} succeeded", probe.name());
+                let name = probe.name().clone();
+                let old = self.announced_hostname.replace(name.clone());
+                if let Some(old) = old.filter(|old| *old != name) {
+                    self.send_host_goodbyes(&old);
+                }
+                self.hostname = Some(name);
+                self.host_announcement = Some(Announcement::new(self.config.announcements));
+            }
+        }
+
+        for id in finished {
+            if let Some(probe) = self.probes.remove(&id) {
+                info!("probing for {} succeeded", probe.name());
+                self.established.insert(probe.name().clone(), id);
+                self.announcements
+                    .insert(id, Announcement::new(self.config.announcements));
+            }
+        }
+
+        let mut links_due = Vec::new();
+        let mut links_finished = Vec::new();
+        for (&index, probe) in self.link_probes.iter_mut() {
+            match probe.step(now) {
+                Some(ProbeStep::Send) => links_due.push(index),
+                Some(ProbeStep::Done) => links_finished.push(index),
+                None => (),
+            }
+        }
+        let established: Vec<usize> = self.established.values().cloned().collect();
+        for index in links_due {
+            let hostname = self.hostname.clone();
+            self.send_probes(&established, hostname.as_ref(), &[Some(index)]);
+        }
+        for index in links_finished {
+            debug!("probing for our names on interface {index} succeeded");
+            self.link_probes.remove(&index);
+            self.link_announcements
+                .insert(index, Announcement::new(self.config.announcements));
+        }
+
+        let mut announce = Vec::new();
+        for (&id, announcement) in self.announcements.iter_mut() {
+            if announcement.step(now) {
+                announce.push(id);
+            }
+        }
+        self.announcements
+            .retain(|_, announcement| !announcement.is_finished());
+
+        let announce_host = match self.host_announcement {
+            Some(ref mut announcement) => announcement.step(now),
+            None => false,
+        };
+        if self.host_announcement.as_ref().is_some_and(Announcement::is_finished) {
+            self.host_announcement = None;
+        }
+
+        if !announce.is_empty() || announce_host {
+            self.send_announcements(&announce, announce_host, &self.multicast_interfaces());
+        }
+
+        let mut links = Vec::new();
+        for (&index, announcement) in self.link_announcements.iter_mut() {
+            if announcement.step(now) {
+                links.push(Some(index));
+            }
+        }
+        self.link_announcements
+            .retain(|_, announcement| !announcement.is_finished());
+        if !links.is_empty() {
+            let host = self.hostname.is_some();
+            self.send_announcements(&established, host, &links);
+        }
+
+        let due: Vec<SocketAddr> = self
+            .truncated
+            .iter()
+            .filter(|(_, query)| query.deadline() <= now)
+            .map(|(&addr, _)| addr)
+            .collect();
+        for addr in due {
+            if let Some(query) = self.truncated.remove(&addr) {
+                self.answer_truncated(query, addr);
+            }
+        }
+
+        let due: Vec<(Option<u32>, Message)> = self
+            .responses
+            .iter_mut()
+            .map(|(&ifindex, responses)| (ifindex, responses.take_due(now)))
+            .filter(|(_, response)| !response.is_empty())
+            .collect();
+        for (ifindex, response) in due {
+            self.send_response(response, ifindex);
+        }
+    }
+
+    fn next_deadline(&self) -> Option<Instant> {
+        let probes = self
+            .probes
+            .values()
+            .chain(self.host_probe.as_ref())
+            .chain(self.link_probes.values())
+            .map(Probe::deadline);
+        let announcements = self
+            .announcements
+            .values()
+            .chain(self.host_announcement.as_ref())
+            .chain(self.link_announcements.values())
+            .map(Announcement::deadline);
+
+        let truncated = self.truncated.values().map(TruncatedQuery::deadline);
+        let responses = self.responses.values().filter_map(PendingResponses::deadline);
+
+        probes
+            .chain(announcements)
+            .chain(truncated)
+            .chain(responses)
+            .min()
+    }
+
+    /// Sends probes out of each of the interfaces `links` covering all the
+    /// given services and the host name `host`, if given: one QU-flagged ANY
+    /// question per name, with the records we intend to use on that link in
+    /// the authority section. Names are spread over as many packets as it
+    /// takes to keep within the maximum message size, each with its records.
+    fn send_probes(&mut self, ids: &[usize], host: Option<&Name<'static>>, links: &[Option<u32>]) {
+        let services = match self.services.read() {
+            Ok(s) => s,
+            Err(e) => {
+                error!("Failed to acquire services lock: {e:?}");
+                return;
+            }
+        };
+
+        for &ifindex in links {
+            let mut parts = Vec::new();
+
+            if let Some(hostname) = host {
+                let mut part = Message::query();
+                part.questions.push(probe_question(hostname));
+                part.authority = self.ip_records(hostname, self.config.ttl.host, ifindex);
+                parts.push(part);
+            }
+
+            for svc in ids.iter().filter_map(|&id| services.find_by_id(id)) {
+                let ttl = self.service_ttl(svc);
+                let mut part = Message::query();
+                part.questions.push(probe_question(&svc.name));
+                part.authority.push(svc.srv_record(services.get_hostname(), ttl.srv));
+                part.authority.push(svc.txt_record(ttl.txt));
+                parts.push(part);
+            }
+
+            let addr = SocketAddr::new(AF::mdns_group(), MDNS_PORT);
+            for message in Message::query().pack(parts, self.config.max_message_size) {
+                self.outgoing.push_back((message.to_bytes(), addr, ifindex));
+            }
+        }
+    }
+
+    /// Sends announcements for the given services and, if `host` is set, for
+    /// our addresses, out of each of the interfaces `links` with the
+    /// addresses of that interface. Host announcements also carry the SRV
+    /// records of every established service, whose target changes when the
+    /// host had to be renamed.
+    fn send_announcements(&mut self, ids: &[usize], host: bool, links: &[Option<u32>]) {
+        let services = match self.services.read() {
+            Ok(s) => s,
+            Err(e) => {
+                error!("Failed to acquire services lock: {e:?}");
+                return;
+            }
+        };
+        let hostname = services.get_hostname();
+        let host_ttl = self.config.ttl.host;
+
+        // services renamed since their announcement was scheduled are
+        // probing again under their new name
+        let established = |id: usize| {
+            services
+                .find_by_id(id)
+                .filter(|svc| self.established.get(&svc.name) == Some(&id))
+        };
+
+        let mut messages = Vec::new();
+        for &ifindex in links {
+            let mut message = Message::response(0);
+
+            for svc in ids.iter().filter_map(|&id| established(id)) {
+                let ttl = self.service_ttl(svc);
+                // services of the same type may differ in their TTLs
+                let type_ptr = svc.type_ptr_record(ttl.ptr);
+                let listed = message.answers.iter().any(|record| {
+                    record.name == type_ptr.name && record.data == type_ptr.data
+                });
+                if !listed {
+                    message.answers.push(type_ptr);
+                }
+                message.answers.push(svc.ptr_record(ttl.ptr));
+                message.answers.extend(svc.subtype_ptr_records(ttl.ptr));
+                message.answers.push(svc.srv_record(hostname, ttl.srv));
+                message.answers.push(svc.txt_record(ttl.txt));
+            }
+
+            if host {
+                let others = self.established.values().filter(|id| !ids.contains(*id));
+                for svc in others.filter_map(|&id| established(id)) {
+                    message.answers.push(svc.srv_record(hostname, self.service_ttl(svc).srv));
                 }
-                IpAddr::V6(ip) if AF::v6() => {
-                    builder = builder.add_answer(hostname, QueryClass::IN, ttl, &RRData::AAAA(ip))
+                if self.hostname.as_ref() == Some(hostname) {
+                    let reverse = self.reverse_records(hostname, host_ttl, ifindex);
+                    message.answers.extend(reverse);
                 }
-                _ => (),
+            }
+
+            if host || !message.is_empty() {
+                self.add_ip_rr(hostname, &mut message.answers, host_ttl, ifindex);
+            }
+
+            if !message.is_empty() {
+                messages.push((ifindex, message));
             }
         }
 
-        builder
+        drop(services);
+
+        let now = Instant::now();
+        let addr = SocketAddr::new(AF::mdns_group(), MDNS_PORT);
+        for (ifindex, message) in messages {
+            let responses = self.responses.entry(ifindex).or_default();
+            responses.multicast(&message.answers, now);
+            self.send_message(message, addr, ifindex);
+        }
     }
 
-    fn send_unsolicited(&mut self, svc: &ServiceData, ttl: u32, include_ip: bool) {
-        let mut builder =
-            dns_parser::Builder::new_response(0, false).move_to::<dns_parser::Answers>();
-        builder.set_max_size(None);
+    /// Multicasts answers whose response delay has passed out of the
+    /// interface with index `ifindex`, if given.
+    fn send_response(&mut self, message: Message, ifindex: Option<u32>) {
+        let records: Vec<Record> = message
+            .answers
+            .iter()
+            .chain(message.additional.iter())
+            .cloned()
+            .collect();
+        let responses = self.responses.entry(ifindex).or_default();
+        responses.multicast(&records, Instant::now());
 
+        let addr = SocketAddr::new(AF::mdns_group(), MDNS_PORT);
+        self.send_message(message, addr, ifindex);
+    }
+
+    /// Queues `message` for `addr`, spread over as many packets as the
+    /// configured maximum message size requires. The packets go out of the
+    /// interface with index `ifindex` if given, and wherever the system
+    /// routes them otherwise.
+    fn send_message(&mut self, message: Message, addr: SocketAddr, ifindex: Option<u32>) {
+        for message in message.split(self.config.max_message_size) {
+            self.outgoing.push_back((message.to_bytes(), addr, ifindex));
+        }
+    }
+
+    /// Sends TTL=0 goodbyes for every established service and for our
+    /// addresses out of each interface, packed into as few packets as
+    /// possible.
+    fn send_goodbyes(&mut self) {
         let services = match self.services.read() {
             Ok(s) => s,
             Err(e) => {
@@ -222,20 +1323,157 @@ impl<AF: AddressFamily> Fsm<AF> {
                 return;
             }
         };
+        let hostname = services.get_hostname();
+
+        let mut messages = Vec::new();
+        for ifindex in self.multicast_interfaces() {
+            let mut message = Message::response(0);
+            for (name, &id) in &self.established {
+                if let Some(svc) = services.find_by_id(id).filter(|svc| svc.name == *name) {
+                    let type_ptr = svc.type_ptr_record(0);
+                    if !message.answers.contains(&type_ptr) {
+                        message.answers.push(type_ptr);
+                    }
+                    message.answers.push(svc.ptr_record(0));
+                    message.answers.extend(svc.subtype_ptr_records(0));
+                    message.answers.push(svc.srv_record(hostname, 0));
+                    message.answers.push(svc.txt_record(0));
+                }
+            }
+            self.add_ip_rr(hostname, &mut message.answers, 0, ifindex);
+            if self.hostname.as_ref() == Some(hostname) {
+                message.answers.extend(self.reverse_records(hostname, 0, ifindex));
+            }
 
-        builder = svc.add_ptr_rr(builder, ttl);
-        builder = svc.add_srv_rr(services.get_hostname(), builder, ttl);
-        builder = svc.add_txt_rr(builder, ttl);
-        if include_ip {
-            builder = self.add_ip_rr(services.get_hostname(), builder, ttl);
+            if !message.is_empty() {
+                messages.push((ifindex, message));
+            }
         }
+        drop(services);
 
-        if !builder.is_empty() {
-            let response = builder.build().unwrap_or_else(|x| x);
-            let addr = SocketAddr::new(AF::mdns_group(), MDNS_PORT);
-            self.outgoing.push_back((response, addr));
+        let addr = SocketAddr::new(AF::mdns_group(), MDNS_PORT);
+        for (ifindex, message) in messages {
+            debug!("sending goodbyes for {} records", message.answers.len());
+            self.send_message(message, addr, ifindex);
         }
     }
+
+    /// Sends TTL=0 goodbyes out of each interface for the address records of
+    /// the host name `old` and their reverse-mapping PTR records, along with
+    /// the SRV records of our services that pointed to it, once the host had
+    /// to be renamed.
+    fn send_host_goodbyes(&mut self, old: &Name<'static>) {
+        let services = match self.services.read() {
+            Ok(s) => s,
+            Err(e) => {
+                error!("Failed to acquire services lock: {e:?}");
+                return;
+            }
+        };
+
+        let mut messages = Vec::new();
+        for ifindex in self.multicast_interfaces() {
+            let mut message = Message::response(0);
+            for (name, &id) in &self.established {
+                if let Some(svc) = services.find_by_id(id).filter(|svc| svc.name == *name) {
+                    message.answers.push(svc.srv_record(old, 0));
+                }
+            }
+            message.answers.extend(self.ip_records(old, 0, ifindex));
+            message.answers.extend(self.reverse_records(old, 0, ifindex));
+
+            if !message.is_empty() {
+                messages.push((ifindex, message));
+            }
+        }
+        drop(services);
+
+        let addr = SocketAddr::new(AF::mdns_group(), MDNS_PORT);
+        for (ifindex, message) in messages {
+            if let Some(responses) = self.responses.get_mut(&ifindex) {
+                for record in &message.answers {
+                    responses.cancel(record);
+                }
+            }
+            debug!("sending goodbyes for the old host name {old}");
+            self.send_message(message, addr, ifindex);
+        }
+    }
+
+    /// Multicasts the records of `svc` out of each interface, with TTL=0 if
+    /// `goodbye` is set, and with our addresses if `include_ip` is set.
+    fn send_unsolicited(&mut self, svc: &ServiceData, goodbye: bool, include_ip: bool) {
+        let services = match self.services.read() {
+            Ok(s) => s,
+            Err(e) => {
+                error!("Failed to acquire services lock: {e:?}");
+                return;
+            }
+        };
+
+        // the type itself goes away with the last service of that type
+        let type_remains = self.established.values().any(|&id| {
+            services
+                .find_by_id(id)
+                .is_some_and(|other| other.typ == svc.typ)
+        });
+
+        let (ttl, host_ttl) = if goodbye {
+            (Ttl::GOODBYE, 0)
+        } else {
+            (self.service_ttl(svc), self.config.ttl.host)
+        };
+
+        let mut messages = Vec::new();
+        for ifindex in self.multicast_interfaces() {
+            let mut message = Message::response(0);
+            if !goodbye || !type_remains {
+                message.answers.push(svc.type_ptr_record(ttl.ptr));
+            }
+            message.answers.push(svc.ptr_record(ttl.ptr));
+            message.answers.extend(svc.subtype_ptr_records(ttl.ptr));
+            message.answers.push(svc.srv_record(services.get_hostname(), ttl.srv));
+            message.answers.push(svc.txt_record(ttl.txt));
+            if include_ip {
+                let hostname = services.get_hostname();
+                self.add_ip_rr(hostname, &mut message.answers, host_ttl, ifindex);
+            }
+            messages.push((ifindex, message));
+        }
+        drop(services);
+
+        if goodbye {
+            for responses in self.responses.values_mut() {
+                for record in messages.iter().flat_map(|(_, message)| &message.answers) {
+                    responses.cancel(record);
+                }
+            }
+        }
+
+        let addr = SocketAddr::new(AF::mdns_group(), MDNS_PORT);
+        for (ifindex, message) in messages {
+            self.send_message(message, addr, ifindex);
+        }
+    }
+}
+
+/// Returns the QU-flagged ANY question used to probe for `name`.
+fn probe_question(name: &Name<'static>) -> packet::Question {
+    packet::Question {
+        name: name.clone(),
+        qtype: packet::TYPE_ANY,
+        unicast: true,
+    }
+}
+
+/// Returns the address record data for `ip` if it's of the address family
+/// `AF`.
+fn address_data<AF: AddressFamily>(ip: IpAddr) -> Option<RData> {
+    match ip {
+        IpAddr::V4(ip) if !AF::v6() => Some(RData::A(ip)),
+        IpAddr::V6(ip) if AF::v6() => Some(RData::AAAA(ip)),
+        _ => None,
+    }
 }
 
 impl<AF: AddressFamily> Future for Fsm<AF> {
@@ -243,31 +1481,80 @@ impl<AF: AddressFamily> Future for Fsm<AF> {
     type Error = io::Error;
     fn poll(&mut self) -> Poll<(), io::Error> {
         // Process commands from the channel
-        while let Ok(Async::Ready(cmd)) = self.commands.poll() {
+        while !self.shutting_down {
+            let cmd = match self.commands.poll() {
+                Ok(Async::Ready(cmd)) => cmd,
+                _ => break,
+            };
+
             match cmd {
-                Some(Command::Shutdown) => return Ok(Async::Ready(())),
+                Some(Command::Shutdown) => {
+                    self.send_goodbyes();
+                    self.shutting_down = true;
+                }
+                Some(Command::Probe { id }) => {
+                    self.start_probe(id);
+                }
                 Some(Command::SendUnsolicited {
                     svc,
-                    ttl,
+                    goodbye,
                     include_ip,
                 }) => {
-                    self.send_unsolicited(&svc, ttl, include_ip);
+                    if goodbye {
+                        // Nothing was announced for a service still probing,
+                        // so there is nothing to say goodbye to either.
+                        self.probes.retain(|_, probe| *probe.name() != svc.name);
+                        match self.established.remove(&svc.name) {
+                            Some(id) => self.announcements.remove(&id),
+                            None => continue,
+                        };
+                    }
+                    self.send_unsolicited(&svc, goodbye, include_ip);
                 }
                 None => {
                     warn!("responder disconnected without shutdown");
-                    return Ok(Async::Ready(()));
+                    self.send_goodbyes();
+                    self.shutting_down = true;
                 }
             }
         }
 
-        while let Async::Ready(()) = self.socket.poll_read() {
+        if !self.shutting_down && self.watcher.poll_changed() {
+            self.refresh_interfaces();
+        } else if !self.watcher.is_active()
+            && self.interface_table.loaded.elapsed() >= INTERFACE_TABLE_MAX_AGE
+        {
+            self.interface_table = InterfaceTable::load();
+        }
+
+        while !self.shutting_down && self.socket.poll_read().is_ready() {
             self.recv_packets()?;
         }
 
-        while let Some((response, addr)) = self.outgoing.front() {
+        while !self.shutting_down {
+            self.run_timers();
+            match self.next_deadline() {
+                Some(deadline) => {
+                    self.timer.reset(deadline);
+                    if let Async::NotReady = self.timer.poll()? {
+                        break;
+                    }
+                }
+                None => break,
+            }
+        }
+
+        while let Some((response, addr, ifindex)) = self.outgoing.front() {
             trace!("sending packet to {addr:?}");
 
-            match self.socket.send_to(response, addr) {
+            let iface = ifindex.and_then(|index| self.interfaces.get(&index));
+            if let Some(iface) = iface.filter(|_| addr.ip().is_multicast()) {
+                if let Err(err) = AF::set_multicast_if(&self.options, iface) {
+                    warn!("could not send out of {}: {err}", iface.name);
+                }
+            }
+
+            match net::send_to(&self.socket, response, addr, *ifindex) {
                 Ok(_) => (),
                 Err(ref ioerr) if ioerr.kind() == WouldBlock => break,
                 Err(err) => warn!("error sending packet {err:?}"),
@@ -276,6 +1563,62 @@ impl<AF: AddressFamily> Future for Fsm<AF> {
             self.outgoing.pop_front();
         }
 
+        // Goodbyes must be on the wire before the responder goes away
+        if self.shutting_down && self.outgoing.is_empty() {
+            return Ok(Async::Ready(()));
+        }
+
         Ok(Async::NotReady)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::address_family::Inet;
+    use crate::services::ServicesInner;
+    use std::sync::RwLock;
+    use tokio_core::reactor::Core;
+
+    fn service(instance: &str) -> ServiceData {
+        ServiceData {
+            name: Name::from_str(format!("{instance}._http._tcp.local")).unwrap(),
+            typ: Name::from_str("_http._tcp.local").unwrap(),
+            port: 80,
+            txt: vec![0],
+            subtypes: Vec::new(),
+            ttl: None,
+        }
+    }
+
+    #[test]
+    fn test_goodbyes_batched() {
+        let core = Core::new().unwrap();
+        let services: Services = Arc::new(RwLock::new(ServicesInner::new("host.local".to_owned())));
+        let counters = Arc::new(Counters::default());
+        let (mut fsm, _commands) =
+            Fsm::<Inet>::new(&core.handle(), &services, &Config::new(), &counters).unwrap();
+
+        for instance in &["first", "second", "third"] {
+            let svc = service(instance);
+            let name = svc.name.clone();
+            let id = services.write().unwrap().register(svc);
+            fsm.established.insert(name, id);
+        }
+        fsm.send_goodbyes();
+
+        let interfaces = fsm.multicast_interfaces();
+        assert!(!interfaces.is_empty());
+        assert_eq!(fsm.outgoing.len(), interfaces.len());
+        for (bytes, _, _) in &fsm.outgoing {
+            let packet = dns_parser::Packet::parse(bytes).unwrap();
+            let srv_count = packet
+                .answers
+                .iter()
+                .filter(|rr| matches!(rr.data, RRData::SRV { .. }))
+                .count();
+            assert_eq!(srv_count, 3);
+            assert!(packet.answers.iter().all(|rr| rr.ttl == 0));
+        }
+    }
+}
@@ -0,0 +1,255 @@
+//! Network interfaces the responder runs on.
+//!
+//! Each address family joins the mDNS group on every interface that is up
+//! and can multicast, rather than only on the system's default multicast
+//! interface, and multicasts go out of each of them in turn. Which
+//! interfaces qualify can be narrowed down with [`InterfaceSelector`]s.
+
+use get_if_addrs::get_if_addrs;
+use log::{debug, error};
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::time::Instant;
+
+use crate::address_policy::Ipv6Flags;
+use crate::net;
+
+/// A network interface and its addresses.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Interface {
+    pub name: String,
+    /// the interface index, or 0 where the platform doesn't report it
+    pub index: u32,
+    /// addresses of both families assigned to the interface
+    pub addrs: Vec<IpAddr>,
+}
+
+impl Interface {
+    /// Returns the first IPv4 address of the interface, which is how IPv4
+    /// multicast socket options identify it.
+    pub fn ipv4(&self) -> Option<Ipv4Addr> {
+        self.addrs.iter().find_map(|ip| match *ip {
+            IpAddr::V4(ip) => Some(ip),
+            IpAddr::V6(_) => None,
+        })
+    }
+}
+
+/// Returns every interface with a non-loopback address, whether or not it
+/// can multicast, with those addresses.
+pub fn all() -> io::Result<Vec<Interface>> {
+    let mut interfaces: Vec<Interface> = Vec::new();
+    for ifaddr in get_if_addrs()? {
+        if ifaddr.is_loopback() {
+            continue;
+        }
+
+        match interfaces.iter_mut().find(|iface| iface.name == ifaddr.name) {
+            Some(iface) => iface.addrs.push(ifaddr.ip()),
+            None => interfaces.push(Interface {
+                index: net::if_index(&ifaddr.name).unwrap_or(0),
+                addrs: vec![ifaddr.ip()],
+                name: ifaddr.name,
+            }),
+        }
+    }
+    Ok(interfaces)
+}
+
+/// Every interface with a non-loopback address, as [`all`] returns them,
+/// along with the flags of their IPv6 addresses, kept so that handling a
+/// packet doesn't mean asking the system for them again.
+pub(crate) struct InterfaceTable {
+    pub interfaces: Vec<Interface>,
+    pub ipv6_flags: HashMap<Ipv6Addr, Ipv6Flags>,
+    /// when the table was loaded
+    pub loaded: Instant,
+}
+
+impl InterfaceTable {
+    /// Loads the table, logging what couldn't be found out. An interface
+    /// list that couldn't be read leaves the table empty.
+    pub fn load() -> InterfaceTable {
+        let interfaces = all().unwrap_or_else(|err| {
+            error!("could not get list of interfaces: {err}");
+            Vec::new()
+        });
+        let ipv6_flags = net::ipv6_flags().unwrap_or_else(|err| {
+            debug!("could not get IPv6 address flags: {err}");
+            HashMap::new()
+        });
+        InterfaceTable {
+            interfaces,
+            ipv6_flags,
+            loaded: Instant::now(),
+        }
+    }
+
+    /// Returns whether `ip` is assigned to one of the interfaces.
+    pub fn has_address(&self, ip: IpAddr) -> bool {
+        self.interfaces.iter().any(|iface| iface.addrs.contains(&ip))
+    }
+}
+
+/// Picks out network interfaces, for
+/// [`Config::allow_interface`](struct.Config.html#method.allow_interface) and
+/// [`Config::deny_interface`](struct.Config.html#method.deny_interface).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum InterfaceSelector {
+    /// The interface with this name, such as `eth0`.
+    Name(String),
+    /// Interfaces whose name matches this pattern, where `*` stands for any
+    /// run of characters and `?` for any single one, such as `veth*`.
+    Glob(String),
+    /// The interface with this index.
+    Index(u32),
+    /// Interfaces with an address in the subnet given by an address and a
+    /// prefix length, such as `10.0.0.0/8`.
+    Subnet(IpAddr, u8),
+}
+
+impl InterfaceSelector {
+    /// Returns whether the selector picks out `iface`.
+    pub(crate) fn matches(&self, iface: &Interface) -> bool {
+        match *self {
+            InterfaceSelector::Name(ref name) => iface.name == *name,
+            InterfaceSelector::Glob(ref pattern) => glob_matches(pattern, &iface.name),
+            InterfaceSelector::Index(index) => iface.index == index,
+            InterfaceSelector::Subnet(net, prefix) => {
+                iface.addrs.iter().any(|&ip| in_subnet(ip, net, prefix))
+            }
+        }
+    }
+
+    /// Returns whether the selector is well-formed, that is, a subnet's
+    /// prefix length isn't longer than its address.
+    pub(crate) fn is_valid(&self) -> bool {
+        match *self {
+            InterfaceSelector::Subnet(IpAddr::V4(_), prefix) => prefix <= 32,
+            InterfaceSelector::Subnet(IpAddr::V6(_), prefix) => prefix <= 128,
+            _ => true,
+        }
+    }
+}
+
+/// Returns whether `name` matches the glob `pattern`.
+fn glob_matches(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+
+    // position of the last `*` in the pattern, and where in the name it
+    // started matching, to backtrack to
+    let mut star = None;
+    let (mut p, mut n) = (0, 0);
+    while n < name.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == name[n]) {
+            p += 1;
+            n += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p, n));
+            p += 1;
+        } else if let Some((star_p, star_n)) = star {
+            // let the `*` swallow one more character
+            p = star_p + 1;
+            n = star_n + 1;
+            star = Some((star_p, star_n + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// Returns whether `ip` lies in the subnet `net`/`prefix`.
+fn in_subnet(ip: IpAddr, net: IpAddr, prefix: u8) -> bool {
+    match (ip, net) {
+        (IpAddr::V4(ip), IpAddr::V4(net)) => {
+            let mask = u32::MAX.checked_shl(32 - u32::from(prefix)).unwrap_or(0);
+            u32::from(ip) & mask == u32::from(net) & mask
+        }
+        (IpAddr::V6(ip), IpAddr::V6(net)) => {
+            let mask = u128::MAX.checked_shl(128 - u32::from(prefix)).unwrap_or(0);
+            u128::from(ip) & mask == u128::from(net) & mask
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv6Addr;
+
+    fn eth0() -> Interface {
+        Interface {
+            name: "eth0".to_owned(),
+            index: 2,
+            addrs: vec![
+                IpAddr::V4(Ipv4Addr::new(192, 168, 1, 2)),
+                IpAddr::V6(Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 1)),
+            ],
+        }
+    }
+
+    #[test]
+    fn test_ipv4() {
+        let mut iface = Interface {
+            name: "eth0".to_owned(),
+            index: 2,
+            addrs: vec![IpAddr::V6(Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 1))],
+        };
+        assert_eq!(iface.ipv4(), None);
+
+        iface.addrs.push(IpAddr::V4(Ipv4Addr::new(192, 168, 1, 2)));
+        iface.addrs.push(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)));
+        assert_eq!(iface.ipv4(), Some(Ipv4Addr::new(192, 168, 1, 2)));
+    }
+
+    #[test]
+    fn test_glob_matches() {
+        assert!(glob_matches("veth*", "veth1a2b"));
+        assert!(glob_matches("veth*", "veth"));
+        assert!(!glob_matches("veth*", "eth0"));
+        assert!(glob_matches("eth?", "eth0"));
+        assert!(!glob_matches("eth?", "eth10"));
+        assert!(glob_matches("*0", "docker0"));
+        assert!(glob_matches("*", ""));
+        assert!(glob_matches("e*h*1", "eth0.1"));
+        assert!(!glob_matches("e*h*2", "eth0.1"));
+    }
+
+    #[test]
+    fn test_in_subnet() {
+        let ip = IpAddr::V4(Ipv4Addr::new(10, 1, 2, 3));
+        assert!(in_subnet(ip, IpAddr::V4(Ipv4Addr::new(10, 0, 0, 0)), 8));
+        assert!(!in_subnet(ip, IpAddr::V4(Ipv4Addr::new(10, 0, 0, 0)), 16));
+        assert!(in_subnet(ip, IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 0));
+        assert!(in_subnet(ip, ip, 32));
+
+        let ip = IpAddr::V6(Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 1));
+        assert!(in_subnet(ip, IpAddr::V6(Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 0)), 10));
+        assert!(!in_subnet(ip, IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 0));
+    }
+
+    #[test]
+    fn test_selector_matches() {
+        assert!(InterfaceSelector::Name("eth0".to_owned()).matches(&eth0()));
+        assert!(!InterfaceSelector::Name("eth".to_owned()).matches(&eth0()));
+        assert!(InterfaceSelector::Glob("eth*".to_owned()).matches(&eth0()));
+        assert!(InterfaceSelector::Index(2).matches(&eth0()));
+        assert!(!InterfaceSelector::Index(3).matches(&eth0()));
+
+        let lan = InterfaceSelector::Subnet(IpAddr::V4(Ipv4Addr::new(192, 168, 0, 0)), 16);
+        assert!(lan.matches(&eth0()));
+        let vpn = InterfaceSelector::Subnet(IpAddr::V4(Ipv4Addr::new(10, 8, 0, 0)), 24);
+        assert!(!vpn.matches(&eth0()));
+    }
+
+    #[test]
+    fn test_selector_is_valid() {
+        assert!(InterfaceSelector::Subnet(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 0)), 32).is_valid());
+        assert!(!InterfaceSelector::Subnet(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 0)), 33).is_valid());
+        assert!(InterfaceSelector::Subnet(IpAddr::V6(Ipv6Addr::LOCALHOST), 128).is_valid());
+    }
+}
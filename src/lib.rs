@@ -37,20 +37,35 @@ use std::thread;
 use tokio::reactor::{Core, Handle};
 
 mod address_family;
+mod address_policy;
+mod announce;
+mod config;
 mod fsm;
+mod interface;
 #[cfg(windows)]
 #[path = "netwin.rs"]
 mod net;
 #[cfg(not(windows))]
 mod net;
+mod packet;
+mod probe;
+mod record;
+mod responses;
 mod services;
+mod stats;
+mod truncated;
+mod watcher;
+
+pub use crate::address_policy::{Address, AddressPolicy};
+pub use crate::config::{Config, Ttl};
+pub use crate::interface::InterfaceSelector;
+pub use crate::stats::Statistics;
 
 use crate::address_family::{Inet, Inet6};
 use crate::fsm::{Command, Fsm};
+use crate::record::{escape_label, is_valid_name, MAX_LABEL_LEN};
 use crate::services::{ServiceData, Services, ServicesInner};
-
-/// Default Time-To-Live for DNS records (in seconds)
-const DEFAULT_TTL: u32 = 60;
+use crate::stats::Counters;
 
 /// Standard mDNS port number
 const MDNS_PORT: u16 = 5353;
@@ -62,6 +77,7 @@ const MDNS_PORT: u16 = 5353;
 /// responses.
 pub struct Responder {
     services: Services,
+    counters: Arc<Counters>,
     commands: RefCell<CommandSender>,
     shutdown: Arc<Shutdown>,
 }
@@ -71,20 +87,37 @@ pub struct Responder {
 /// When this handle is dropped, the service will be unregistered and will stop
 /// being advertised on the network. Keep this handle alive as long as you want
 /// the service to be discoverable.
+///
+/// The handle keeps the responder running after the `Responder` itself is
+/// dropped. The goodbyes for the last service to go are sent together with
+/// those for anything else still advertised when the responder shuts down.
 pub struct Service {
     id: usize,
     services: Services,
     commands: CommandSender,
-    _shutdown: Arc<Shutdown>,
+    shutdown: Arc<Shutdown>,
+}
+
+/// Changes the responder made on its own, reported through
+/// [`Responder::events`](struct.Responder.html#method.events).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Event {
+    /// Another host on the network already used our host name, so the
+    /// responder switched to a new one. SRV records of all services now point
+    /// to the new name.
+    HostnameChanged { old: String, new: String },
+    /// Another host on the network already used a service instance name, so
+    /// the service was renamed.
+    ServiceRenamed { old: String, new: String },
 }
 
 type ResponderTask = Box<dyn Future<Item = (), Error = io::Error> + Send>;
 
 impl Responder {
     /// Internal helper to set up the tokio event loop core
-    fn setup_core() -> io::Result<(Core, ResponderTask, Responder)> {
+    fn setup_core(config: Config) -> io::Result<(Core, ResponderTask, Responder)> {
         let core = Core::new()?;
-        let (responder, task) = Self::with_handle(&core.handle())?;
+        let (responder, task) = Self::with_handle_and_config(&core.handle(), config)?;
         Ok((core, task, responder))
     }
 
@@ -101,10 +134,18 @@ impl Responder {
     /// - Network interfaces cannot be accessed
     /// - The background thread fails to start
     pub fn new() -> io::Result<Responder> {
+        Self::with_config(Config::default())
+    }
+
+    /// Creates a new mDNS responder with its own background thread, using
+    /// the given configuration.
+    ///
+    /// See [`new`](#method.new) for details.
+    pub fn with_config(config: Config) -> io::Result<Responder> {
         let (tx, rx) = std::sync::mpsc::sync_channel(0);
         let handle = thread::Builder::new()
             .name("mdns-responder".to_owned())
-            .spawn(move || match Self::setup_core() {
+            .spawn(move || match Self::setup_core(config) {
                 Ok((mut core, task, responder)) => {
                     tx.send(Ok(responder)).expect("tx responder channel closed");
                     core.run(task).expect("mdns thread failed");
@@ -130,7 +171,15 @@ impl Responder {
     ///
     /// * `handle` - A handle to the tokio reactor where tasks will be spawned
     pub fn spawn(handle: &Handle) -> io::Result<Responder> {
-        let (responder, task) = Responder::with_handle(handle)?;
+        Self::spawn_with_config(handle, Config::default())
+    }
+
+    /// Creates a new mDNS responder using an existing tokio event loop and
+    /// the given configuration.
+    ///
+    /// See [`spawn`](#method.spawn) for details.
+    pub fn spawn_with_config(handle: &Handle, config: Config) -> io::Result<Responder> {
+        let (responder, task) = Responder::with_handle_and_config(handle, config)?;
         handle.spawn(task.map_err(|e| {
             warn!("mdns error {e:?}");
             
@@ -147,15 +196,27 @@ impl Responder {
     ///
     /// * `handle` - A handle to the tokio reactor
     pub fn with_handle(handle: &Handle) -> io::Result<(Responder, ResponderTask)> {
+        Self::with_handle_and_config(handle, Config::default())
+    }
+
+    /// Creates a new mDNS responder with a custom tokio handle and the given
+    /// configuration.
+    ///
+    /// See [`with_handle`](#method.with_handle) for details.
+    pub fn with_handle_and_config(
+        handle: &Handle,
+        config: Config,
+    ) -> io::Result<(Responder, ResponderTask)> {
         let mut hostname = net::gethostname()?;
         if !hostname.ends_with(".local") {
             hostname.push_str(".local");
         }
 
         let services = Arc::new(RwLock::new(ServicesInner::new(hostname)));
+        let counters = Arc::new(Counters::default());
 
-        let v4 = Fsm::<Inet>::new(handle, &services);
-        let v6 = Fsm::<Inet6>::new(handle, &services);
+        let v4 = Fsm::<Inet>::new(handle, &services, &config, &counters);
+        let v6 = Fsm::<Inet6>::new(handle, &services, &config, &counters);
 
         let (task, commands): (ResponderTask, _) = match (v4, v6) {
             (Ok((v4_task, v4_command)), Ok((v6_task, v6_command))) => {
@@ -177,6 +238,7 @@ impl Responder {
         let commands = CommandSender(commands);
         let responder = Responder {
             services,
+            counters,
             commands: RefCell::new(commands.clone()),
             shutdown: Arc::new(Shutdown {
                 commands: commands.clone(),
@@ -224,43 +286,204 @@ impl Responder {
     /// A `Service` handle that keeps the service registered. The service will
     /// be automatically unregistered when this handle is dropped.
     ///
+    /// The service is not advertised right away: the responder first probes
+    /// the network to make sure no other host uses the same instance name,
+    /// and only answers for the service once that succeeds. It is then
+    /// announced as many times as [`Config::announcements`] says.
+    ///
+    /// [`Config::announcements`]: struct.Config.html#method.announcements
+    ///
+    /// The service name may contain dots, e.g. "My.Printer".
+    ///
     /// # Panics
     ///
-    /// Panics if any TXT record entry is longer than 255 bytes.
+    /// Panics if the service name or a label of the service type is longer
+    /// than 63 bytes, or if any TXT record entry is longer than 255 bytes.
     pub fn register(&self, svc_type: String, svc_name: String, port: u16, txt: &[&str]) -> Service {
+        self.register_with_subtypes(svc_type, svc_name, port, txt, &[])
+    }
+
+    /// Registers a new service that is also listed under the given subtypes.
+    ///
+    /// Browsers looking for a subtype, e.g. "_printer" of "_http._tcp",
+    /// query "_printer._sub._http._tcp.local" and only find the services
+    /// registered with it (RFC 6763 §7.1).
+    ///
+    /// See [`register`](#method.register) for the other arguments.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # use mdns_responder_rs as mdns;
+    /// # fn main() -> std::io::Result<()> {
+    /// let responder = mdns::Responder::new()?;
+    /// let _service = responder.register_with_subtypes(
+    ///     "_http._tcp".to_owned(),
+    ///     "Office Printer".to_owned(),
+    ///     80,
+    ///     &["path=/"],
+    ///     &["_printer"],
+    /// );
+    /// # Ok(())
+    /// # }
+    /// ```
+    ///
+    /// # Panics
+    ///
+    /// Panics if the service name or a label of the service type is longer
+    /// than 63 bytes, or if any TXT record entry is longer than 255 bytes.
+    pub fn register_with_subtypes(
+        &self,
+        svc_type: String,
+        svc_name: String,
+        port: u16,
+        txt: &[&str],
+        subtypes: &[&str],
+    ) -> Service {
+        assert!(
+            svc_name.len() <= MAX_LABEL_LEN,
+            "service name is longer than {} bytes: {}",
+            MAX_LABEL_LEN,
+            svc_name
+        );
+        let typ = Name::from_str(format!("{svc_type}.local")).expect("Invalid service type format");
+        assert!(
+            is_valid_name(&typ),
+            "service type has a label longer than {} bytes: {}",
+            MAX_LABEL_LEN,
+            svc_type
+        );
         let txt = build_txt_record(txt);
 
         let svc = ServiceData {
-            typ: Name::from_str(format!("{svc_type}.local"))
-                .expect("Invalid service type format"),
-            name: Name::from_str(format!("{svc_name}.{svc_type}.local"))
+            typ,
+            name: Name::from_str(format!("{}.{svc_type}.local", escape_label(&svc_name)))
                 .expect("Invalid service name format"),
             port,
             txt,
+            subtypes: subtypes
+                .iter()
+                .map(|subtype| {
+                    Name::from_str(format!("{subtype}._sub.{svc_type}.local"))
+                        .expect("Invalid subtype format")
+                })
+                .collect(),
+            ttl: None,
         };
 
-        self.commands
-            .borrow_mut()
-            .send_unsolicited(svc.clone(), DEFAULT_TTL, true);
-
         let id = self.services.write().unwrap().register(svc);
 
+        self.commands.borrow_mut().send_probe(id);
+
         Service {
             id,
             commands: self.commands.borrow().clone(),
             services: self.services.clone(),
-            _shutdown: self.shutdown.clone(),
+            shutdown: self.shutdown.clone(),
         }
     }
 }
 
+impl Responder {
+    /// Sets how services are renamed when their name turns out to be in use.
+    ///
+    /// The policy receives the instance name the service was registered with
+    /// and the attempt number, starting at 2, and returns the instance name
+    /// to try next. The default policy produces "Name (2)", "Name (3)", ...
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # use mdns_responder_rs as mdns;
+    /// # fn main() -> std::io::Result<()> {
+    /// let responder = mdns::Responder::new()?;
+    /// responder.set_rename_policy(|name, attempt| format!("{name} #{attempt}"));
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn set_rename_policy<F>(&self, policy: F)
+    where
+        F: Fn(&str, u32) -> String + Send + Sync + 'static,
+    {
+        self.services
+            .write()
+            .unwrap()
+            .set_rename_policy(Box::new(policy));
+    }
+}
+
+impl Responder {
+    /// Returns a channel receiving [`Event`](enum.Event.html)s about changes
+    /// the responder makes on its own, such as renaming the host after a
+    /// conflict.
+    ///
+    /// Every call creates a new channel; events that happened before the call
+    /// are not replayed.
+    pub fn events(&self) -> std::sync::mpsc::Receiver<Event> {
+        self.services.write().unwrap().subscribe()
+    }
+
+    /// Returns the host name the responder currently advertises, e.g.
+    /// "myhost.local".
+    pub fn hostname(&self) -> String {
+        self.services.read().unwrap().get_hostname().to_string()
+    }
+
+    /// Returns how many answers the responder suppressed so far, which helps
+    /// telling a chatty network from a misbehaving peer.
+    pub fn statistics(&self) -> Statistics {
+        self.counters.snapshot()
+    }
+}
+
+impl Service {
+    /// Returns the instance name the service is currently advertised under.
+    ///
+    /// This differs from the name passed to
+    /// [`Responder::register`](struct.Responder.html#method.register) if
+    /// another host already used that name and the service had to be renamed.
+    pub fn name(&self) -> String {
+        self.services
+            .read()
+            .unwrap()
+            .find_by_id(self.id)
+            .expect("unknown service")
+            .instance()
+    }
+
+    /// Sets the TTLs of the service's PTR, SRV, TXT and NSEC records,
+    /// overriding [`Config::ttl`]. The host's address records are shared by
+    /// all services, so `ttl.host` is ignored here.
+    ///
+    /// Records sent from then on use the new TTLs. Setting them right after
+    /// registering covers the service's announcements as well.
+    ///
+    /// [`Config::ttl`]: struct.Config.html#method.ttl
+    ///
+    /// # Panics
+    ///
+    /// Panics if any of the TTLs is 0.
+    pub fn set_ttl(&self, ttl: Ttl) {
+        assert!(ttl.is_valid(), "TTLs must not be 0, got {:?}", ttl);
+        self.services.write().unwrap().set_ttl(self.id, ttl);
+    }
+}
+
 impl Drop for Service {
     fn drop(&mut self) {
+        // the responder shuts down along with the last handle, saying goodbye
+        // for this service with the rest
+        if Arc::strong_count(&self.shutdown) == 1 {
+            return;
+        }
         let svc = self.services.write().unwrap().unregister(self.id);
-        self.commands.send_unsolicited(svc, 0, false);
+        self.commands.send_unsolicited(svc, true, false);
     }
 }
 
+/// Stops the responders once the `Responder` and all `Service` handles are
+/// gone. Before stopping, they multicast goodbyes for everything they still
+/// advertise, batched into as few packets as fit.
 struct Shutdown {
     commands: CommandSender,
     thread_handle: Mutex<Option<thread::JoinHandle<()>>>,
@@ -287,10 +510,14 @@ impl CommandSender {
         }
     }
 
-    fn send_unsolicited(&mut self, svc: ServiceData, ttl: u32, include_ip: bool) {
+    fn send_probe(&mut self, id: usize) {
+        self.send(Command::Probe { id });
+    }
+
+    fn send_unsolicited(&mut self, svc: ServiceData, goodbye: bool, include_ip: bool) {
         self.send(Command::SendUnsolicited {
             svc,
-            ttl,
+            goodbye,
             include_ip,
         });
     }
@@ -303,6 +530,7 @@ impl CommandSender {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use futures::Stream;
 
     #[test]
     fn test_build_txt_record_empty() {
@@ -355,4 +583,35 @@ mod tests {
         ];
         assert_eq!(result, expected);
     }
+
+    #[test]
+    fn test_goodbyes_batched_on_shutdown() {
+        let (tx, rx) = mpsc::unbounded();
+        let commands = CommandSender(vec![tx]);
+        let responder = Responder {
+            services: Arc::new(RwLock::new(ServicesInner::new("host.local".to_owned()))),
+            counters: Arc::new(Counters::default()),
+            commands: RefCell::new(commands.clone()),
+            shutdown: Arc::new(Shutdown {
+                commands,
+                thread_handle: Mutex::new(None),
+            }),
+        };
+        let services = responder.services.clone();
+        let first = responder.register("_http._tcp".to_owned(), "first".to_owned(), 80, &[]);
+        let second = responder.register("_http._tcp".to_owned(), "second".to_owned(), 80, &[]);
+
+        drop(responder);
+        drop(first);
+        drop(second);
+
+        // the first service says goodbye on its own while the responder keeps
+        // running for the second, whose goodbye the state machine sends on
+        // shutdown, as it's still registered then
+        assert_eq!(services.read().unwrap().iter().count(), 1);
+        let commands: Vec<Command> = rx.wait().map(Result::unwrap).collect();
+        assert_eq!(commands.len(), 4);
+        assert!(matches!(commands[2], Command::SendUnsolicited { goodbye: true, .. }));
+        assert!(matches!(commands[3], Command::Shutdown));
+    }
 }
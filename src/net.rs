@@ -1,3 +1,12 @@
+use crate::address_family::PacketInfo;
+use crate::address_policy::Ipv6Flags;
+use crate::interface::Interface;
+use std::collections::HashMap;
+use std::net::Ipv6Addr;
+
+/// The socket the state machines send and receive on. Unlike tokio's own
+/// `UdpSocket`, it lets us wait for readiness around `recvmsg` and `sendmsg`.
+pub type Socket = tokio_core::reactor::PollEvented<mio::net::UdpSocket>;
 
 pub fn gethostname() -> std::io::Result<String> {
     unsafe {
@@ -17,3 +26,399 @@ pub fn gethostname() -> std::io::Result<String> {
         Ok(String::from_utf8_lossy(&name).into_owned())
     }
 }
+
+/// Asks the kernel to report the destination address and interface of
+/// received packets. The address tells queries sent to the mDNS group apart
+/// from ones sent to our own address, the interface which of our addresses
+/// the querier can reach.
+#[cfg(target_os = "linux")]
+pub fn set_pktinfo(socket: &std::net::UdpSocket, v6: bool) -> std::io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let (level, name) = if v6 {
+        (libc::IPPROTO_IPV6, libc::IPV6_RECVPKTINFO)
+    } else {
+        (libc::IPPROTO_IP, libc::IP_PKTINFO)
+    };
+    let on: libc::c_int = 1;
+
+    let ret = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            level,
+            name,
+            &on as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if ret < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn set_pktinfo(_socket: &std::net::UdpSocket, _v6: bool) -> std::io::Result<()> {
+    Ok(())
+}
+
+/// Receives a packet, returning its length, its source and, where the
+/// platform reports it, the address and interface it arrived on.
+#[cfg(target_os = "linux")]
+pub fn recv_from(
+    socket: &Socket,
+    buf: &mut [u8],
+) -> std::io::Result<(usize, std::net::SocketAddr, Option<PacketInfo>)> {
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+    use std::os::unix::io::AsRawFd;
+
+    if socket.poll_read().is_not_ready() {
+        return Err(std::io::ErrorKind::WouldBlock.into());
+    }
+
+    unsafe {
+        let mut addr: libc::sockaddr_storage = std::mem::zeroed();
+        let mut iov = libc::iovec {
+            iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+            iov_len: buf.len(),
+        };
+        // u64 for the alignment cmsghdr needs
+        let mut control = [0u64; 16];
+
+        let mut msg: libc::msghdr = std::mem::zeroed();
+        msg.msg_name = &mut addr as *mut libc::sockaddr_storage as *mut libc::c_void;
+        msg.msg_namelen = std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = std::mem::size_of_val(&control) as _;
+
+        let len = libc::recvmsg(socket.get_ref().as_raw_fd(), &mut msg, 0);
+        if len < 0 {
+            let err = std::io::Error::last_os_error();
+            if err.kind() == std::io::ErrorKind::WouldBlock {
+                socket.need_read();
+            }
+            return Err(err);
+        }
+
+        let src = match i32::from(addr.ss_family) {
+            libc::AF_INET => {
+                let addr = &*(&addr as *const libc::sockaddr_storage as *const libc::sockaddr_in);
+                let ip = Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr));
+                SocketAddr::V4(SocketAddrV4::new(ip, u16::from_be(addr.sin_port)))
+            }
+            libc::AF_INET6 => {
+                let addr = &*(&addr as *const libc::sockaddr_storage as *const libc::sockaddr_in6);
+                SocketAddr::V6(SocketAddrV6::new(
+                    Ipv6Addr::from(addr.sin6_addr.s6_addr),
+                    u16::from_be(addr.sin6_port),
+                    addr.sin6_flowinfo,
+                    addr.sin6_scope_id,
+                ))
+            }
+            family => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("unexpected address family {family}"),
+                ))
+            }
+        };
+
+        let mut info = None;
+        let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+        while !cmsg.is_null() {
+            let data = libc::CMSG_DATA(cmsg);
+            match ((*cmsg).cmsg_level, (*cmsg).cmsg_type) {
+                (libc::IPPROTO_IP, libc::IP_PKTINFO) => {
+                    let pktinfo = std::ptr::read_unaligned(data as *const libc::in_pktinfo);
+                    let ip = Ipv4Addr::from(u32::from_be(pktinfo.ipi_addr.s_addr));
+                    info = Some(PacketInfo {
+                        dst: IpAddr::V4(ip),
+                        ifindex: pktinfo.ipi_ifindex as u32,
+                    });
+                }
+                (libc::IPPROTO_IPV6, libc::IPV6_PKTINFO) => {
+                    let pktinfo = std::ptr::read_unaligned(data as *const libc::in6_pktinfo);
+                    info = Some(PacketInfo {
+                        dst: IpAddr::V6(Ipv6Addr::from(pktinfo.ipi6_addr.s6_addr)),
+                        ifindex: pktinfo.ipi6_ifindex,
+                    });
+                }
+                _ => (),
+            }
+            cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+        }
+
+        Ok((len as usize, src, info))
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn recv_from(
+    socket: &Socket,
+    buf: &mut [u8],
+) -> std::io::Result<(usize, std::net::SocketAddr, Option<PacketInfo>)> {
+    if socket.poll_read().is_not_ready() {
+        return Err(std::io::ErrorKind::WouldBlock.into());
+    }
+    match socket.get_ref().recv_from(buf) {
+        Ok((len, addr)) => Ok((len, addr, None)),
+        Err(err) => {
+            if err.kind() == std::io::ErrorKind::WouldBlock {
+                socket.need_read();
+            }
+            Err(err)
+        }
+    }
+}
+
+/// Sends a packet to `addr`, out of the interface with index `ifindex` if
+/// one is given.
+#[cfg(target_os = "linux")]
+pub fn send_to(
+    socket: &Socket,
+    buf: &[u8],
+    addr: &std::net::SocketAddr,
+    ifindex: Option<u32>,
+) -> std::io::Result<usize> {
+    use std::net::SocketAddr;
+    use std::os::unix::io::AsRawFd;
+
+    let ifindex = match ifindex {
+        Some(ifindex) => ifindex,
+        None => return send_plain(socket, buf, addr),
+    };
+    if socket.poll_write().is_not_ready() {
+        return Err(std::io::ErrorKind::WouldBlock.into());
+    }
+
+    unsafe {
+        let mut name: libc::sockaddr_storage = std::mem::zeroed();
+        let storage = &mut name as *mut libc::sockaddr_storage;
+        let namelen = match *addr {
+            SocketAddr::V4(addr) => {
+                let sin = &mut *(storage as *mut libc::sockaddr_in);
+                sin.sin_family = libc::AF_INET as libc::sa_family_t;
+                sin.sin_port = addr.port().to_be();
+                sin.sin_addr.s_addr = u32::from(*addr.ip()).to_be();
+                std::mem::size_of::<libc::sockaddr_in>()
+            }
+            SocketAddr::V6(addr) => {
+                let sin6 = &mut *(storage as *mut libc::sockaddr_in6);
+                sin6.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+                sin6.sin6_port = addr.port().to_be();
+                sin6.sin6_addr.s6_addr = addr.ip().octets();
+                sin6.sin6_flowinfo = addr.flowinfo();
+                sin6.sin6_scope_id = addr.scope_id();
+                std::mem::size_of::<libc::sockaddr_in6>()
+            }
+        };
+
+        let mut iov = libc::iovec {
+            iov_base: buf.as_ptr() as *mut libc::c_void,
+            iov_len: buf.len(),
+        };
+        // u64 for the alignment cmsghdr needs
+        let mut control = [0u64; 8];
+
+        let mut msg: libc::msghdr = std::mem::zeroed();
+        msg.msg_name = storage as *mut libc::c_void;
+        msg.msg_namelen = namelen as libc::socklen_t;
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+
+        // the kernel picks the source address matching the interface
+        let (level, kind, len) = match *addr {
+            SocketAddr::V4(_) => (
+                libc::IPPROTO_IP,
+                libc::IP_PKTINFO,
+                std::mem::size_of::<libc::in_pktinfo>(),
+            ),
+            SocketAddr::V6(_) => (
+                libc::IPPROTO_IPV6,
+                libc::IPV6_PKTINFO,
+                std::mem::size_of::<libc::in6_pktinfo>(),
+            ),
+        };
+        msg.msg_controllen = libc::CMSG_SPACE(len as u32) as _;
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        (*cmsg).cmsg_level = level;
+        (*cmsg).cmsg_type = kind;
+        (*cmsg).cmsg_len = libc::CMSG_LEN(len as u32) as _;
+        let data = libc::CMSG_DATA(cmsg);
+        match *addr {
+            SocketAddr::V4(_) => {
+                let mut pktinfo: libc::in_pktinfo = std::mem::zeroed();
+                pktinfo.ipi_ifindex = ifindex as libc::c_int;
+                std::ptr::write_unaligned(data as *mut libc::in_pktinfo, pktinfo);
+            }
+            SocketAddr::V6(_) => {
+                let mut pktinfo: libc::in6_pktinfo = std::mem::zeroed();
+                pktinfo.ipi6_ifindex = ifindex;
+                std::ptr::write_unaligned(data as *mut libc::in6_pktinfo, pktinfo);
+            }
+        }
+
+        let len = libc::sendmsg(socket.get_ref().as_raw_fd(), &msg, 0);
+        if len < 0 {
+            let err = std::io::Error::last_os_error();
+            if err.kind() == std::io::ErrorKind::WouldBlock {
+                // the packet is sent again once the socket is writable
+                socket.need_write();
+            }
+            return Err(err);
+        }
+        Ok(len as usize)
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn send_to(
+    socket: &Socket,
+    buf: &[u8],
+    addr: &std::net::SocketAddr,
+    _ifindex: Option<u32>,
+) -> std::io::Result<usize> {
+    send_plain(socket, buf, addr)
+}
+
+/// Sends a packet to `addr`, leaving the choice of interface to the system.
+fn send_plain(
+    socket: &Socket,
+    buf: &[u8],
+    addr: &std::net::SocketAddr,
+) -> std::io::Result<usize> {
+    if socket.poll_write().is_not_ready() {
+        return Err(std::io::ErrorKind::WouldBlock.into());
+    }
+    socket.get_ref().send_to(buf, addr).inspect_err(|err| {
+        if err.kind() == std::io::ErrorKind::WouldBlock {
+            socket.need_write();
+        }
+    })
+}
+
+/// Returns the index of the interface called `name`.
+pub fn if_index(name: &str) -> Option<u32> {
+    let name = std::ffi::CString::new(name).ok()?;
+    match unsafe { libc::if_nametoindex(name.as_ptr()) } {
+        0 => None,
+        index => Some(index),
+    }
+}
+
+/// Returns the interfaces that are up and can multicast, leaving out
+/// loopback.
+pub fn interfaces() -> std::io::Result<Vec<Interface>> {
+    use nix::ifaddrs::getifaddrs;
+    use nix::net::if_::InterfaceFlags;
+    use nix::sys::socket::SockAddr;
+
+    let ifaddrs = getifaddrs().map_err(std::io::Error::other)?;
+
+    let mut interfaces: Vec<Interface> = Vec::new();
+    for ifaddr in ifaddrs {
+        let flags = ifaddr.flags;
+        if !flags.contains(InterfaceFlags::IFF_UP | InterfaceFlags::IFF_MULTICAST)
+            || flags.contains(InterfaceFlags::IFF_LOOPBACK)
+        {
+            continue;
+        }
+
+        // one entry per address, plus one for the link itself
+        let position = interfaces
+            .iter()
+            .position(|iface| iface.name == ifaddr.interface_name);
+        let iface = match position {
+            Some(position) => &mut interfaces[position],
+            None => match if_index(&ifaddr.interface_name) {
+                Some(index) => {
+                    interfaces.push(Interface {
+                        name: ifaddr.interface_name.clone(),
+                        index,
+                        addrs: Vec::new(),
+                    });
+                    interfaces.last_mut().unwrap()
+                }
+                None => continue,
+            },
+        };
+
+        if let Some(SockAddr::Inet(addr)) = ifaddr.address {
+            iface.addrs.push(addr.to_std().ip());
+        }
+    }
+
+    Ok(interfaces)
+}
+
+/// Returns whether each IPv6 address is temporary or deprecated, as listed
+/// in /proc/net/if_inet6.
+#[cfg(target_os = "linux")]
+pub fn ipv6_flags() -> std::io::Result<HashMap<Ipv6Addr, Ipv6Flags>> {
+    let table = std::fs::read_to_string("/proc/net/if_inet6")?;
+    Ok(parse_if_inet6(&table))
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn ipv6_flags() -> std::io::Result<HashMap<Ipv6Addr, Ipv6Flags>> {
+    Ok(HashMap::new())
+}
+
+/// Parses lines of the form `address ifindex prefixlen scope flags name`,
+/// with every number but the name in hex.
+#[cfg(target_os = "linux")]
+fn parse_if_inet6(table: &str) -> HashMap<Ipv6Addr, Ipv6Flags> {
+    // from linux/if_addr.h
+    const IFA_F_TEMPORARY: u32 = 0x01;
+    const IFA_F_DEPRECATED: u32 = 0x20;
+
+    let mut flags = HashMap::new();
+    for line in table.lines() {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() < 6 || fields[0].len() != 32 {
+            continue;
+        }
+        let addr = match u128::from_str_radix(fields[0], 16) {
+            Ok(addr) => Ipv6Addr::from(addr),
+            Err(_) => continue,
+        };
+        let bits = match u32::from_str_radix(fields[4], 16) {
+            Ok(bits) => bits,
+            Err(_) => continue,
+        };
+        flags.insert(
+            addr,
+            Ipv6Flags {
+                temporary: bits & IFA_F_TEMPORARY != 0,
+                deprecated: bits & IFA_F_DEPRECATED != 0,
+            },
+        );
+    }
+    flags
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_if_inet6() {
+        let table = "\
+20010db8000000000000000000000001 02 40 00 80     eth0
+20010db800000000000000000000abcd 02 40 00 01     eth0
+fe800000000000000000000000000001 02 40 20 a0     eth0
+";
+        let flags = parse_if_inet6(table);
+        assert_eq!(flags.len(), 3);
+
+        let stable = flags[&"2001:db8::1".parse::<Ipv6Addr>().unwrap()];
+        assert!(!stable.temporary && !stable.deprecated);
+        let temporary = flags[&"2001:db8::abcd".parse::<Ipv6Addr>().unwrap()];
+        assert!(temporary.temporary && !temporary.deprecated);
+        let deprecated = flags[&"fe80::1".parse::<Ipv6Addr>().unwrap()];
+        assert!(!deprecated.temporary && deprecated.deprecated);
+    }
+}
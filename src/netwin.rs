@@ -4,6 +4,15 @@ extern crate winapi;
 
 use std;
 
+use crate::address_family::PacketInfo;
+use crate::address_policy::Ipv6Flags;
+use crate::interface::Interface;
+use std::collections::HashMap;
+use std::net::Ipv6Addr;
+
+/// The socket the state machines send and receive on.
+pub type Socket = tokio_core::reactor::PollEvented<mio::net::UdpSocket>;
+
 pub fn gethostname() -> std::io::Result<String> {
     const MAX_COMPUTERNAME_LENGTH: usize = 15;
 
@@ -20,3 +29,60 @@ pub fn gethostname() -> std::io::Result<String> {
 
     Ok(String::from_utf8_lossy(&host).into_owned())
 }
+
+pub fn set_pktinfo(_socket: &std::net::UdpSocket, _v6: bool) -> std::io::Result<()> {
+    Ok(())
+}
+
+/// Receives a packet. The address and interface it arrived on aren't
+/// available on Windows.
+pub fn recv_from(
+    socket: &Socket,
+    buf: &mut [u8],
+) -> std::io::Result<(usize, std::net::SocketAddr, Option<PacketInfo>)> {
+    if socket.poll_read().is_not_ready() {
+        return Err(std::io::ErrorKind::WouldBlock.into());
+    }
+    match socket.get_ref().recv_from(buf) {
+        Ok((len, addr)) => Ok((len, addr, None)),
+        Err(err) => {
+            if err.kind() == std::io::ErrorKind::WouldBlock {
+                socket.need_read();
+            }
+            Err(err)
+        }
+    }
+}
+
+/// Sends a packet, leaving the choice of interface to the system.
+pub fn send_to(
+    socket: &Socket,
+    buf: &[u8],
+    addr: &std::net::SocketAddr,
+    _ifindex: Option<u32>,
+) -> std::io::Result<usize> {
+    if socket.poll_write().is_not_ready() {
+        return Err(std::io::ErrorKind::WouldBlock.into());
+    }
+    socket.get_ref().send_to(buf, addr).inspect_err(|err| {
+        if err.kind() == std::io::ErrorKind::WouldBlock {
+            socket.need_write();
+        }
+    })
+}
+
+pub fn if_index(_name: &str) -> Option<u32> {
+    None
+}
+
+/// Interfaces aren't enumerated on Windows, so the mDNS group is only joined
+/// on the system's default interface.
+pub fn interfaces() -> std::io::Result<Vec<Interface>> {
+    Ok(Vec::new())
+}
+
+/// Windows doesn't tell which addresses are temporary or deprecated here, so
+/// address policies see them all as stable.
+pub fn ipv6_flags() -> std::io::Result<HashMap<Ipv6Addr, Ipv6Flags>> {
+    Ok(HashMap::new())
+}
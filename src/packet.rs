@@ -0,0 +1,541 @@
+//! Serialization of outgoing mDNS messages.
+//!
+//! `dns_parser::Builder` only writes plain IN class records, which leaves no
+//! way to set the cache-flush bit (RFC 6762 §10.2) on unique records. Outgoing
+//! messages are therefore assembled from owned [`Record`]s and serialized
+//! here, while incoming packets are still parsed with `dns_parser`.
+
+use dns_parser::Name;
+use log::warn;
+use std::collections::HashMap;
+
+use crate::record::{self, RData, Record, MAX_LABEL_LEN};
+
+/// The IN class
+pub const CLASS_IN: u16 = 1;
+
+/// Question type matching all record types
+pub const TYPE_ANY: u16 = 255;
+
+/// Default size of the largest message we send, which fits a standard
+/// Ethernet MTU once IP and UDP headers are added
+pub const DEFAULT_MESSAGE_SIZE: usize = 1460;
+
+/// Largest message mDNS allows even on links with jumbo frames
+/// (RFC 6762 §17)
+pub const MESSAGE_SIZE_LIMIT: usize = 9000;
+
+/// Top bit of the class field: cache-flush on records, unicast-response on
+/// questions
+const CLASS_TOP_BIT: u16 = 0x8000;
+
+/// Length of the fixed message header
+const HEADER_LEN: usize = 12;
+
+/// A question in an outgoing query.
+#[derive(Clone, Debug)]
+pub struct Question {
+    pub name: Name<'static>,
+    pub qtype: u16,
+    /// ask for a unicast response (the QU bit)
+    pub unicast: bool,
+}
+
+/// An outgoing DNS message.
+#[derive(Clone, Debug, Default)]
+pub struct Message {
+    pub id: u16,
+    pub response: bool,
+    /// set the cache-flush bit on unique records; only ever honored in
+    /// responses, never in queries and the probes among them
+    pub cache_flush: bool,
+    pub questions: Vec<Question>,
+    pub answers: Vec<Record>,
+    pub authority: Vec<Record>,
+    pub additional: Vec<Record>,
+}
+
+impl Message {
+    /// Creates an empty query.
+    pub fn query() -> Message {
+        Message::default()
+    }
+
+    /// Creates an empty authoritative response, which sets the cache-flush
+    /// bit on its unique records.
+    pub fn response(id: u16) -> Message {
+        Message {
+            id,
+            response: true,
+            cache_flush: true,
+            ..Message::default()
+        }
+    }
+
+    /// Returns true if the message carries no questions and no records.
+    pub fn is_empty(&self) -> bool {
+        self.questions.is_empty()
+            && self.answers.is_empty()
+            && self.authority.is_empty()
+            && self.additional.is_empty()
+    }
+
+    /// Removes additional records that repeat an answer or an earlier
+    /// additional record, as happens when several questions lead to the same
+    /// records.
+    pub fn dedup_additional(&mut self) {
+        let mut seen = self.answers.clone();
+        self.additional.retain(|record| {
+            if seen.contains(record) {
+                return false;
+            }
+            seen.push(record.clone());
+            true
+        });
+    }
+
+    /// Spreads the records of this message over as many messages as needed
+    /// to keep each one within `max_size` bytes.
+    ///
+    /// The most important records go first: answers, then authority records,
+    /// then additional records ordered SRV, TXT, addresses and the rest. The
+    /// questions are repeated in every message. The TC bit is never set, as
+    /// the follow-up messages are complete responses of their own.
+    pub fn split(mut self, max_size: usize) -> Vec<Message> {
+        self.additional.sort_by_key(additional_rank);
+
+        let sections = [
+            std::mem::take(&mut self.answers),
+            std::mem::take(&mut self.authority),
+            std::mem::take(&mut self.additional),
+        ];
+        let empty = self.clone();
+
+        // the message is encoded as it grows, the same way to_bytes would
+        let start = || {
+            let mut writer = Writer::default();
+            writer.buf.resize(HEADER_LEN, 0);
+            for question in empty.questions.iter().filter(|q| record::is_valid_name(&q.name)) {
+                writer.question(question);
+            }
+            writer
+        };
+
+        let mut messages = Vec::new();
+        let mut current = self;
+        let mut writer = start();
+        for (index, records) in sections.iter().enumerate() {
+            for record in records.iter() {
+                current.section_mut(index).push(record.clone());
+                if !is_writable(record) {
+                    continue;
+                }
+
+                writer.record(record, false);
+                if current.record_count() > 1 && writer.buf.len() > max_size {
+                    current.section_mut(index).pop();
+                    messages.push(std::mem::replace(&mut current, empty.clone()));
+                    current.section_mut(index).push(record.clone());
+                    writer = start();
+                    writer.record(record, false);
+                }
+            }
+        }
+        messages.push(current);
+
+        messages
+    }
+
+    /// Combines `parts` into as few messages as needed to keep each one
+    /// within `max_size` bytes, taking the header from this message. The
+    /// questions and records of a part always end up in the same message,
+    /// which a part too large on its own gets to itself.
+    pub fn pack(&self, parts: Vec<Message>, max_size: usize) -> Vec<Message> {
+        let mut messages = Vec::new();
+        let mut current = self.clone();
+        let mut size = HEADER_LEN;
+        for part in parts {
+            // names compressed against other parts only get shorter
+            let part_size = part.to_bytes().len() - HEADER_LEN;
+            if !current.is_empty() && size + part_size > max_size {
+                messages.push(std::mem::replace(&mut current, self.clone()));
+                size = HEADER_LEN;
+            }
+            size += part_size;
+            current.questions.extend(part.questions);
+            current.answers.extend(part.answers);
+            current.authority.extend(part.authority);
+            current.additional.extend(part.additional);
+        }
+        if !current.is_empty() {
+            messages.push(current);
+        }
+
+        messages
+    }
+
+    fn section_mut(&mut self, index: usize) -> &mut Vec<Record> {
+        match index {
+            0 => &mut self.answers,
+            1 => &mut self.authority,
+            _ => &mut self.additional,
+        }
+    }
+
+    fn record_count(&self) -> usize {
+        self.answers.len() + self.authority.len() + self.additional.len()
+    }
+
+    /// Serializes the message, compressing names where possible.
+    ///
+    /// Questions and records with a name that has a label over 63 bytes
+    /// can't be written and are left out.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let questions: Vec<&Question> = self
+            .questions
+            .iter()
+            .filter(|question| record::is_valid_name(&question.name))
+            .collect();
+        let sections: Vec<Vec<&Record>> = [&self.answers, &self.authority, &self.additional]
+            .iter()
+            .map(|section| section.iter().filter(|record| is_writable(record)).collect())
+            .collect();
+
+        let written = questions.len() + sections.iter().map(Vec::len).sum::<usize>();
+        if written < self.questions.len() + self.record_count() {
+            warn!("leaving out names with labels over {MAX_LABEL_LEN} bytes");
+        }
+
+        let mut writer = Writer::default();
+
+        writer.u16(self.id);
+        // QR and AA for responses, a standard query otherwise
+        writer.u16(if self.response { 0x8400 } else { 0 });
+        writer.u16(questions.len() as u16);
+        for section in &sections {
+            writer.u16(section.len() as u16);
+        }
+
+        for question in questions {
+            writer.question(question);
+        }
+
+        for record in sections.iter().flatten() {
+            writer.record(record, self.response && self.cache_flush && record.unique);
+        }
+
+        writer.buf
+    }
+}
+
+/// Orders additional records by how soon a querier needs them (RFC 6763
+/// §12): the SRV record tells where the service is, the TXT record how to
+/// use it, and the addresses how to reach the SRV target.
+fn additional_rank(record: &Record) -> u8 {
+    match record.data {
+        RData::SRV { .. } => 0,
+        RData::TXT(_) => 1,
+        RData::A(_) | RData::AAAA(_) => 2,
+        _ => 3,
+    }
+}
+
+/// Returns true if every name in `record` can be written.
+fn is_writable(record: &Record) -> bool {
+    let data_name = match record.data {
+        RData::PTR(ref name) | RData::NSEC { next: ref name, .. } => Some(name),
+        RData::SRV { ref target, .. } => Some(target),
+        _ => None,
+    };
+    record::is_valid_name(&record.name) && data_name.is_none_or(record::is_valid_name)
+}
+
+#[derive(Default)]
+struct Writer {
+    buf: Vec<u8>,
+    /// offsets of names already written, by their labels, for compression
+    names: HashMap<Vec<Vec<u8>>, u16>,
+}
+
+impl Writer {
+    fn u16(&mut self, value: u16) {
+        self.buf.extend_from_slice(&value.to_be_bytes());
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.buf.extend_from_slice(&value.to_be_bytes());
+    }
+
+    fn name(&mut self, name: &Name) {
+        let labels = record::labels(name);
+
+        for i in 0..labels.len() {
+            let suffix = &labels[i..];
+            if let Some(&offset) = self.names.get(suffix) {
+                self.u16(0xc000 | offset);
+                return;
+            }
+
+            // pointers only have 14 bits for the offset
+            if self.buf.len() < 0x4000 {
+                self.names.insert(suffix.to_vec(), self.buf.len() as u16);
+            }
+            self.buf.push(labels[i].len() as u8);
+            self.buf.extend_from_slice(&labels[i]);
+        }
+        self.buf.push(0);
+    }
+
+    fn question(&mut self, question: &Question) {
+        self.name(&question.name);
+        self.u16(question.qtype);
+        let top = if question.unicast { CLASS_TOP_BIT } else { 0 };
+        self.u16(CLASS_IN | top);
+    }
+
+    fn record(&mut self, record: &Record, cache_flush: bool) {
+        self.name(&record.name);
+        self.u16(record.data.rtype());
+        let top = if cache_flush { CLASS_TOP_BIT } else { 0 };
+        self.u16(CLASS_IN | top);
+        self.u32(record.ttl);
+
+        let len_offset = self.buf.len();
+        self.u16(0);
+        match record.data {
+            RData::PTR(ref name) => self.name(name),
+            RData::SRV {
+                priority,
+                weight,
+                port,
+                ref target,
+            } => {
+                self.u16(priority);
+                self.u16(weight);
+                self.u16(port);
+                self.name(target);
+            }
+            ref data => self.buf.extend_from_slice(&data.to_bytes()),
+        }
+
+        let len = (self.buf.len() - len_offset - 2) as u16;
+        self.buf[len_offset..len_offset + 2].copy_from_slice(&len.to_be_bytes());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    fn host() -> Name<'static> {
+        Name::from_str("host.local").unwrap()
+    }
+
+    #[test]
+    fn test_empty_response() {
+        let message = Message::response(0x1234);
+        assert!(message.is_empty());
+        assert_eq!(message.to_bytes(), vec![0x12, 0x34, 0x84, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_cache_flush_bit() {
+        let mut message = Message::response(0);
+        message.answers.push(Record::unique(host(), 120, RData::A(Ipv4Addr::new(10, 0, 0, 1))));
+
+        let flushed = message.to_bytes();
+        message.cache_flush = false;
+        let plain = message.to_bytes();
+
+        // header, then 4host5local0, then type and class
+        let class = 12 + 12 + 2;
+        assert_eq!(&plain[class..class + 2], &[0x00, 0x01]);
+        assert_eq!(&flushed[class..class + 2], &[0x80, 0x01]);
+    }
+
+    #[test]
+    fn test_shared_records_never_flush() {
+        let mut message = Message::response(0);
+        message.answers.push(Record::shared(
+            Name::from_str("_http._tcp.local").unwrap(),
+            4500,
+            RData::PTR(Name::from_str("web._http._tcp.local").unwrap()),
+        ));
+
+        let bytes = message.to_bytes();
+        // header, then 5_http4_tcp5local0, then type and class
+        let class = 12 + 18 + 2;
+        assert_eq!(&bytes[class..class + 2], &[0x00, 0x01]);
+    }
+
+    #[test]
+    fn test_queries_never_flush() {
+        let mut message = Message::query();
+        message.cache_flush = true;
+        message.authority.push(Record::unique(host(), 120, RData::A(Ipv4Addr::new(10, 0, 0, 1))));
+
+        let bytes = message.to_bytes();
+        let class = 12 + 12 + 2;
+        assert_eq!(&bytes[class..class + 2], &[0x00, 0x01]);
+    }
+
+    #[test]
+    fn test_dedup_additional() {
+        let a = |last| Record::unique(host(), 120, RData::A(Ipv4Addr::new(10, 0, 0, last)));
+        let mut message = Message::response(0);
+        message.answers.push(a(1));
+        message.additional = vec![a(1), a(2), a(3), a(2)];
+
+        message.dedup_additional();
+        assert_eq!(message.additional, vec![a(2), a(3)]);
+    }
+
+    #[test]
+    fn test_split() {
+        let mut message = Message::response(0);
+        for i in 0..100 {
+            message.answers.push(Record::unique(host(), 0, RData::A(Ipv4Addr::new(10, 0, 0, i))));
+        }
+
+        let messages = message.split(200);
+        assert!(messages.len() > 1);
+        assert_eq!(messages.iter().map(|m| m.answers.len()).sum::<usize>(), 100);
+        for message in &messages {
+            assert!(message.to_bytes().len() <= 200);
+        }
+    }
+
+    #[test]
+    fn test_split_fills_messages() {
+        let mut message = Message::response(0);
+        for i in 0..50 {
+            let name = Name::from_str(format!("host{i}.local")).unwrap();
+            let a = RData::A(Ipv4Addr::new(10, 0, 0, i));
+            message.answers.push(Record::unique(name.clone(), 120, a));
+            message.additional.push(Record::unique(name, 120, RData::TXT(vec![0; i as usize])));
+        }
+
+        let messages = message.split(300);
+        for pair in messages.windows(2) {
+            assert!(pair[0].to_bytes().len() <= 300);
+            // the next record didn't fit anymore
+            let mut fuller = pair[0].clone();
+            match pair[1].answers.first() {
+                Some(record) => fuller.answers.push(record.clone()),
+                None => fuller.additional.push(pair[1].additional[0].clone()),
+            }
+            assert!(fuller.to_bytes().len() > 300);
+        }
+        let count: usize = messages.iter().map(|m| m.answers.len() + m.additional.len()).sum();
+        assert_eq!(count, 100);
+    }
+
+    #[test]
+    fn test_split_keeps_priority_order() {
+        let a = |last| Record::unique(host(), 120, RData::A(Ipv4Addr::new(10, 0, 0, last)));
+        let txt = Record::unique(host(), 120, RData::TXT(vec![0; 100]));
+
+        let mut message = Message::response(0);
+        for i in 0..20 {
+            message.answers.push(a(i));
+        }
+        message.additional.push(a(100));
+        message.additional.push(txt.clone());
+
+        let messages = message.split(200);
+        let additional: Vec<&Record> = messages.iter().flat_map(|m| m.additional.iter()).collect();
+        assert_eq!(additional, vec![&txt, &a(100)]);
+
+        // answers all come before any additional record
+        let last_answer = messages.iter().rposition(|m| !m.answers.is_empty()).unwrap();
+        let first_additional = messages.iter().position(|m| !m.additional.is_empty()).unwrap();
+        assert!(last_answer <= first_additional);
+    }
+
+    #[test]
+    fn test_split_repeats_questions() {
+        let mut message = Message::response(7);
+        message.questions.push(Question {
+            name: host(),
+            qtype: 1,
+            unicast: false,
+        });
+        for i in 0..100 {
+            message.answers.push(Record::unique(host(), 10, RData::A(Ipv4Addr::new(10, 0, 0, i))));
+        }
+
+        let messages = message.split(512);
+        assert!(messages.len() > 1);
+        for message in &messages {
+            assert_eq!(message.id, 7);
+            assert_eq!(message.questions.len(), 1);
+            assert!(message.to_bytes().len() <= 512);
+        }
+    }
+
+    #[test]
+    fn test_pack() {
+        let part = |i: u8| {
+            let name = Name::from_str(format!("host{i}.local")).unwrap();
+            let mut part = Message::query();
+            part.questions.push(Question {
+                name: name.clone(),
+                qtype: TYPE_ANY,
+                unicast: true,
+            });
+            part.authority.push(Record::unique(name, 120, RData::TXT(vec![0; 50])));
+            part
+        };
+
+        let messages = Message::query().pack((0..20).map(part).collect(), 512);
+        assert!(messages.len() > 1);
+        for message in &messages {
+            assert!(message.to_bytes().len() <= 512);
+            assert_eq!(message.questions.len(), message.authority.len());
+            for (question, record) in message.questions.iter().zip(&message.authority) {
+                assert_eq!(question.name, record.name);
+            }
+        }
+        assert_eq!(messages.iter().map(|m| m.questions.len()).sum::<usize>(), 20);
+        assert!(Message::query().pack(Vec::new(), 512).is_empty());
+    }
+
+    #[test]
+    fn test_name_compression() {
+        let mut message = Message::response(0);
+        let a = Record::unique(host(), 120, RData::A(Ipv4Addr::new(10, 0, 0, 1)));
+        message.answers.push(a.clone());
+        message.answers.push(a);
+
+        let bytes = message.to_bytes();
+        // the second record's name is a pointer to offset 12
+        let second = 12 + 12 + 10 + 4;
+        assert_eq!(&bytes[second..second + 2], &[0xc0, 12]);
+        assert_eq!(bytes.len(), second + 2 + 10 + 4);
+    }
+
+    #[test]
+    fn test_escaped_dot_stays_in_label() {
+        let mut message = Message::response(0);
+        let instance = Name::from_str("My\\.Printer._ipp._tcp.local").unwrap();
+        message.answers.push(Record::unique(instance, 120, RData::TXT(vec![0])));
+
+        let bytes = message.to_bytes();
+        assert_eq!(&bytes[12..23], b"\x0aMy.Printer");
+        assert_eq!(&bytes[23..28], b"\x04_ipp");
+    }
+
+    #[test]
+    fn test_long_labels_left_out() {
+        let mut message = Message::response(0);
+        let long = Name::from_str(format!("{}.local", "a".repeat(64))).unwrap();
+        message.answers.push(Record::unique(long, 120, RData::A(Ipv4Addr::new(10, 0, 0, 1))));
+        message.answers.push(Record::unique(host(), 120, RData::A(Ipv4Addr::new(10, 0, 0, 2))));
+
+        let bytes = message.to_bytes();
+        // one answer, for host.local
+        assert_eq!(&bytes[6..8], &[0, 1]);
+        assert_eq!(bytes.len(), 12 + 12 + 10 + 4);
+    }
+}
@@ -0,0 +1,186 @@
+//! Probing for unique record names (RFC 6762 §8.1).
+//!
+//! Before a service is answered or announced, the responder asks the network
+//! three times whether anybody else already owns its instance name. Only when
+//! nobody objected is the name considered ours.
+
+use dns_parser::{Name, Packet};
+use rand::{thread_rng, Rng};
+use std::cmp::Ordering;
+use std::time::{Duration, Instant};
+
+use crate::record::Record;
+
+/// Number of probe queries sent before a name is considered unique
+pub const PROBE_COUNT: u8 = 3;
+
+/// Interval between two probe queries for the same name
+pub const PROBE_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Delay before probing again after losing a simultaneous probe tiebreak
+pub const PROBE_DEFER: Duration = Duration::from_secs(1);
+
+/// What a probe wants done after its deadline has passed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProbeStep {
+    /// Another probe query must be sent.
+    Send,
+    /// All probes went unanswered, the name is ours.
+    Done,
+}
+
+/// Probing progress for a single name.
+#[derive(Debug)]
+pub struct Probe {
+    name: Name<'static>,
+    sent: u8,
+    deadline: Instant,
+}
+
+impl Probe {
+    /// Starts probing `name` after a random delay of up to 250ms, so that
+    /// hosts powered on at the same time don't probe in lockstep.
+    pub fn new(name: Name<'static>) -> Probe {
+        let delay = Duration::from_millis(thread_rng().gen_range(0, 250));
+        Probe::deferred(name, delay)
+    }
+
+    /// Starts probing `name` once `delay` has elapsed.
+    pub fn deferred(name: Name<'static>, delay: Duration) -> Probe {
+        Probe {
+            name,
+            sent: 0,
+            deadline: Instant::now() + delay,
+        }
+    }
+
+    /// Returns the name being probed.
+    pub fn name(&self) -> &Name<'static> {
+        &self.name
+    }
+
+    /// Returns when this probe next needs attention.
+    pub fn deadline(&self) -> Instant {
+        self.deadline
+    }
+
+    /// Advances the probe if its deadline has passed.
+    pub fn step(&mut self, now: Instant) -> Option<ProbeStep> {
+        if now < self.deadline {
+            return None;
+        }
+
+        if self.sent >= PROBE_COUNT {
+            return Some(ProbeStep::Done);
+        }
+
+        self.sent += 1;
+        self.deadline = now + PROBE_INTERVAL;
+        Some(ProbeStep::Send)
+    }
+}
+
+/// Compares two sets of records proposed for the same name by simultaneous
+/// probes, as described in RFC 6762 §8.2.
+///
+/// Returns `Ordering::Less` if `ours` loses the tiebreak and we must defer to
+/// the other host, `Ordering::Equal` if both sets are identical (which
+/// happens when we see our own probes looped back).
+pub fn tiebreak(ours: &[Record], theirs: &[Record]) -> Ordering {
+    fn sorted(records: &[Record]) -> Vec<(u16, Vec<u8>)> {
+        let mut keys: Vec<_> = records
+            .iter()
+            .map(|record| (record.data.rtype(), record.data.to_bytes()))
+            .collect();
+        keys.sort();
+        keys
+    }
+
+    sorted(ours).cmp(&sorted(theirs))
+}
+
+/// Returns the records another host's probe in `packet` proposes for `name`,
+/// which it lists in the authority section.
+pub fn proposed_records(packet: &Packet, name: &Name) -> Vec<Record> {
+    packet
+        .nameservers
+        .iter()
+        .filter(|rr| rr.name == *name)
+        .filter_map(Record::from_rr)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet::{self, Message};
+    use crate::record::RData;
+    use std::net::Ipv4Addr;
+
+    fn a_record(last: u8) -> Record {
+        Record::unique(
+            Name::from_str("host.local").unwrap(),
+            120,
+            RData::A(Ipv4Addr::new(169, 254, 99, last)),
+        )
+    }
+
+    #[test]
+    fn test_probe_sequence() {
+        let mut probe = Probe::deferred(Name::from_str("test.local").unwrap(), Duration::from_secs(0));
+        let mut now = probe.deadline();
+
+        for _ in 0..PROBE_COUNT {
+            assert_eq!(probe.step(now), Some(ProbeStep::Send));
+            assert_eq!(probe.step(now), None);
+            now += PROBE_INTERVAL;
+        }
+
+        assert_eq!(probe.step(now), Some(ProbeStep::Done));
+    }
+
+    #[test]
+    fn test_tiebreak_rfc_example() {
+        // The example from RFC 6762 §8.2: 169.254.99.200 beats 169.254.99.100.
+        assert_eq!(tiebreak(&[a_record(200)], &[a_record(100)]), Ordering::Greater);
+        assert_eq!(tiebreak(&[a_record(100)], &[a_record(200)]), Ordering::Less);
+    }
+
+    #[test]
+    fn test_tiebreak_identical() {
+        assert_eq!(tiebreak(&[a_record(1)], &[a_record(1)]), Ordering::Equal);
+    }
+
+    #[test]
+    fn test_tiebreak_more_records_wins() {
+        let ours = [a_record(1), a_record(2)];
+        assert_eq!(tiebreak(&ours, &[a_record(1)]), Ordering::Greater);
+    }
+
+    #[test]
+    fn test_simultaneous_probe() {
+        let name = Name::from_str("host.local").unwrap();
+        let mut message = Message::query();
+        message.questions.push(packet::Question {
+            name: name.clone(),
+            qtype: packet::TYPE_ANY,
+            unicast: true,
+        });
+        message.authority.push(a_record(200));
+        // records for other names in the same probe don't take part
+        message.authority.push(Record::unique(
+            Name::from_str("other.local").unwrap(),
+            120,
+            RData::A(Ipv4Addr::new(169, 254, 99, 1)),
+        ));
+
+        let bytes = message.to_bytes();
+        let packet = Packet::parse(&bytes).unwrap();
+        let theirs = proposed_records(&packet, &name);
+
+        assert_eq!(theirs.len(), 1);
+        assert_eq!(tiebreak(&[a_record(100)], &theirs), Ordering::Less);
+        assert_eq!(tiebreak(&[a_record(200)], &theirs), Ordering::Equal);
+        assert!(proposed_records(&packet, &Name::from_str("none.local").unwrap()).is_empty());
+    }
+}
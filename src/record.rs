@@ -0,0 +1,354 @@
+//! Owned resource records published by the responder.
+//!
+//! `dns_parser` only hands out records borrowed from a received packet, so the
+//! responder keeps its own copy of everything it advertises. This lets us
+//! compare our records against the ones other hosts put on the wire, which
+//! probing and conflict detection depend on.
+
+use dns_parser::{Name, RRData, ResourceRecord};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+/// Record data for the record types the responder publishes.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[allow(clippy::upper_case_acronyms)]
+pub enum RData {
+    A(Ipv4Addr),
+    AAAA(Ipv6Addr),
+    PTR(Name<'static>),
+    SRV {
+        priority: u16,
+        weight: u16,
+        port: u16,
+        target: Name<'static>,
+    },
+    TXT(Vec<u8>),
+    /// the restricted form of NSEC used by mDNS (RFC 6762 §6.1): `next` is
+    /// the owner name itself, and only types below 256 are listed
+    NSEC {
+        next: Name<'static>,
+        types: Vec<u16>,
+    },
+}
+
+impl RData {
+    /// Converts parsed record data into an owned copy.
+    ///
+    /// Returns `None` for record types the responder never publishes.
+    pub fn from_rr(data: &RRData) -> Option<RData> {
+        let data = match *data {
+            RRData::A(ip) => RData::A(ip),
+            RRData::AAAA(ip) => RData::AAAA(ip),
+            RRData::PTR(ref name) => RData::PTR(Name::from_str(name.to_string()).ok()?),
+            RRData::SRV {
+                priority,
+                weight,
+                port,
+                ref target,
+            } => RData::SRV {
+                priority,
+                weight,
+                port,
+                target: Name::from_str(target.to_string()).ok()?,
+            },
+            RRData::TXT(txt) => RData::TXT(txt.to_vec()),
+            _ => return None,
+        };
+        Some(data)
+    }
+
+    /// Returns the numeric DNS type of this record data.
+    pub fn rtype(&self) -> u16 {
+        match *self {
+            RData::A(_) => 1,
+            RData::PTR(_) => 12,
+            RData::TXT(_) => 16,
+            RData::AAAA(_) => 28,
+            RData::SRV { .. } => 33,
+            RData::NSEC { .. } => 47,
+        }
+    }
+
+    /// Encodes the record data in its uncompressed wire format.
+    ///
+    /// This is the representation RFC 6762 §8.2 uses to order records when
+    /// breaking ties between simultaneous probes.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        match *self {
+            RData::A(ip) => out.extend_from_slice(&ip.octets()),
+            RData::AAAA(ip) => out.extend_from_slice(&ip.octets()),
+            RData::PTR(ref name) => write_name(name, &mut out),
+            RData::SRV {
+                priority,
+                weight,
+                port,
+                ref target,
+            } => {
+                out.extend_from_slice(&priority.to_be_bytes());
+                out.extend_from_slice(&weight.to_be_bytes());
+                out.extend_from_slice(&port.to_be_bytes());
+                write_name(target, &mut out);
+            }
+            RData::TXT(ref txt) => out.extend_from_slice(txt),
+            RData::NSEC {
+                ref next,
+                ref types,
+            } => {
+                write_name(next, &mut out);
+                out.extend_from_slice(&nsec_bitmap(types));
+            }
+        }
+        out
+    }
+}
+
+/// A single resource record owned by the responder.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Record {
+    pub name: Name<'static>,
+    pub ttl: u32,
+    pub data: RData,
+    /// whether this is the only record set for its name and type on the
+    /// network (RFC 6762 §2), as opposed to a shared one like a PTR record
+    pub unique: bool,
+}
+
+impl Record {
+    /// Creates a unique record, such as an SRV, TXT or address record.
+    pub fn unique(name: Name<'static>, ttl: u32, data: RData) -> Record {
+        Record {
+            name,
+            ttl,
+            data,
+            unique: true,
+        }
+    }
+
+    /// Creates a shared record, such as a service PTR record.
+    pub fn shared(name: Name<'static>, ttl: u32, data: RData) -> Record {
+        Record {
+            name,
+            ttl,
+            data,
+            unique: false,
+        }
+    }
+
+    /// Creates the NSEC record asserting that `name` has records of the
+    /// given types and no others.
+    pub fn nsec(name: Name<'static>, ttl: u32, mut types: Vec<u16>) -> Record {
+        types.sort();
+        types.dedup();
+        let data = RData::NSEC {
+            next: name.clone(),
+            types,
+        };
+        Record::unique(name, ttl, data)
+    }
+
+    /// Returns true if a querier already listed this record among its known
+    /// answers with at least half of its TTL left, in which case we must not
+    /// send it (RFC 6762 §7.1).
+    pub fn is_known(&self, known_answers: &[Record]) -> bool {
+        known_answers.iter().any(|known| {
+            known.name == self.name
+                && known.data == self.data
+                && u64::from(known.ttl) * 2 >= u64::from(self.ttl)
+        })
+    }
+
+    /// Converts a parsed resource record into an owned copy.
+    ///
+    /// Whether the other host considers the record unique isn't tracked, the
+    /// copy is only meant for comparisons with our own records.
+    pub fn from_rr(rr: &ResourceRecord) -> Option<Record> {
+        Some(Record::shared(
+            Name::from_str(rr.name.to_string()).ok()?,
+            rr.ttl,
+            RData::from_rr(&rr.data)?,
+        ))
+    }
+}
+
+/// Returns the reverse-mapping name of `ip`, e.g. "2.1.168.192.in-addr.arpa"
+/// for 192.168.1.2, or the nibble form under "ip6.arpa" for IPv6.
+pub fn reverse_name(ip: IpAddr) -> Name<'static> {
+    let name = match ip {
+        IpAddr::V4(ip) => {
+            let octets = ip.octets();
+            format!("{}.{}.{}.{}.in-addr.arpa", octets[3], octets[2], octets[1], octets[0])
+        }
+        IpAddr::V6(ip) => {
+            let mut name = String::new();
+            for octet in ip.octets().iter().rev() {
+                name.push_str(&format!("{:x}.{:x}.", octet & 0xf, octet >> 4));
+            }
+            name.push_str("ip6.arpa");
+            name
+        }
+    };
+    Name::from_str(name).expect("Invalid reverse name")
+}
+
+/// Encodes `types` as an NSEC type bitmap. mDNS only uses window block 0, so
+/// types of 256 and above are left out.
+fn nsec_bitmap(types: &[u16]) -> Vec<u8> {
+    let mut bitmap = [0u8; 32];
+    for &rtype in types.iter().filter(|&&rtype| rtype < 256) {
+        bitmap[usize::from(rtype / 8)] |= 0x80 >> (rtype % 8);
+    }
+
+    // trailing zero octets are left out, and so is an empty block
+    let len = match bitmap.iter().rposition(|&octet| octet != 0) {
+        Some(last) => last + 1,
+        None => return Vec::new(),
+    };
+    let mut out = vec![0, len as u8];
+    out.extend_from_slice(&bitmap[..len]);
+    out
+}
+
+/// Longest label a name may have (RFC 1035 §2.3.4)
+pub const MAX_LABEL_LEN: usize = 63;
+
+/// Escapes dots and backslashes in `label`, so that it stays a single label
+/// once made part of a name: "My.Printer" becomes "My\.Printer". Service
+/// instance names may contain dots (RFC 6763 §4.3).
+pub fn escape_label(label: &str) -> String {
+    let mut escaped = String::with_capacity(label.len());
+    for c in label.chars() {
+        if c == '.' || c == '\\' {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Splits `name` into its labels, undoing the escapes added by
+/// [`escape_label`](fn.escape_label.html).
+pub fn labels(name: &Name) -> Vec<Vec<u8>> {
+    let name = name.to_string();
+    let mut labels = Vec::new();
+    let mut label = String::new();
+    let mut chars = name.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => label.extend(chars.next()),
+            '.' => labels.push(std::mem::take(&mut label).into_bytes()),
+            c => label.push(c),
+        }
+    }
+    labels.push(label.into_bytes());
+    labels.retain(|label| !label.is_empty());
+    labels
+}
+
+/// Returns true if no label of `name` is longer than
+/// [`MAX_LABEL_LEN`](constant.MAX_LABEL_LEN.html), so that it can be written
+/// to a message.
+pub fn is_valid_name(name: &Name) -> bool {
+    labels(name).iter().all(|label| label.len() <= MAX_LABEL_LEN)
+}
+
+/// Writes `name` as a sequence of uncompressed labels. Names that aren't
+/// [valid](fn.is_valid_name.html) are turned away before they get here.
+fn write_name(name: &Name, out: &mut Vec<u8>) {
+    debug_assert!(is_valid_name(name), "label too long in {}", name);
+    for label in labels(name) {
+        out.push(label.len() as u8);
+        out.extend_from_slice(&label);
+    }
+    out.push(0);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rtype() {
+        assert_eq!(RData::A(Ipv4Addr::new(10, 0, 0, 1)).rtype(), 1);
+        assert_eq!(RData::AAAA(Ipv6Addr::LOCALHOST).rtype(), 28);
+        assert_eq!(RData::TXT(vec![0]).rtype(), 16);
+    }
+
+    #[test]
+    fn test_is_known() {
+        let name = Name::from_str("host.local").unwrap();
+        let ours = Record::unique(name.clone(), 120, RData::A(Ipv4Addr::new(10, 0, 0, 1)));
+
+        let fresh = Record::shared(name.clone(), 60, RData::A(Ipv4Addr::new(10, 0, 0, 1)));
+        let stale = Record::shared(name.clone(), 59, RData::A(Ipv4Addr::new(10, 0, 0, 1)));
+        let other = Record::shared(name, 120, RData::A(Ipv4Addr::new(10, 0, 0, 2)));
+
+        assert!(ours.is_known(&[fresh]));
+        assert!(!ours.is_known(&[stale]));
+        assert!(!ours.is_known(&[other]));
+        assert!(!ours.is_known(&[]));
+    }
+
+    #[test]
+    fn test_nsec_bitmap() {
+        // A and AAAA, as in the example of RFC 6762 §6.1
+        assert_eq!(nsec_bitmap(&[1, 28]), vec![0, 4, 0x40, 0, 0, 0x08]);
+        // TXT and SRV
+        assert_eq!(nsec_bitmap(&[16, 33]), vec![0, 5, 0, 0, 0x80, 0, 0x40]);
+        assert_eq!(nsec_bitmap(&[]), Vec::<u8>::new());
+    }
+
+    #[test]
+    fn test_nsec_record() {
+        let name = Name::from_str("host.local").unwrap();
+        let nsec = Record::nsec(name.clone(), 120, vec![28, 1, 1]);
+
+        assert!(nsec.unique);
+        assert_eq!(nsec.data.rtype(), 47);
+        assert_eq!(nsec.data, RData::NSEC { next: name, types: vec![1, 28] });
+    }
+
+    #[test]
+    fn test_reverse_name() {
+        let v4 = reverse_name(IpAddr::V4(Ipv4Addr::new(192, 168, 1, 2)));
+        assert_eq!(v4.to_string(), "2.1.168.192.in-addr.arpa");
+
+        let v6 = reverse_name(IpAddr::V6("2001:db8::567:89ab".parse().unwrap()));
+        assert_eq!(
+            v6.to_string(),
+            "b.a.9.8.7.6.5.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.8.b.d.0.1.0.0.2.ip6.arpa"
+        );
+    }
+
+    #[test]
+    fn test_a_to_bytes() {
+        let data = RData::A(Ipv4Addr::new(192, 168, 1, 2));
+        assert_eq!(data.to_bytes(), vec![192, 168, 1, 2]);
+    }
+
+    #[test]
+    fn test_srv_to_bytes() {
+        let data = RData::SRV {
+            priority: 0,
+            weight: 0,
+            port: 8080,
+            target: Name::from_str("host.local".to_owned()).unwrap(),
+        };
+        let expected = vec![
+            0, 0, 0, 0, 0x1f, 0x90,
+            4, b'h', b'o', b's', b't', 5, b'l', b'o', b'c', b'a', b'l', 0,
+        ];
+        assert_eq!(data.to_bytes(), expected);
+    }
+
+    #[test]
+    fn test_labels() {
+        let instance = escape_label("My.Printer\\1");
+        let name = Name::from_str(format!("{instance}._ipp._tcp.local")).unwrap();
+        let expected: Vec<&[u8]> = vec![b"My.Printer\\1", b"_ipp", b"_tcp", b"local"];
+        assert_eq!(labels(&name), expected);
+        assert!(is_valid_name(&name));
+
+        let long = Name::from_str(format!("{}.local", "a".repeat(64))).unwrap();
+        assert!(!is_valid_name(&long));
+    }
+}
@@ -0,0 +1,345 @@
+//! Multicast answers waiting to be sent.
+//!
+//! Answers aren't multicast the moment a query is handled but go through this
+//! table first. Unique records are due right away, while shared records such
+//! as PTRs wait 20–120ms so that several responders don't all answer at the
+//! same instant (RFC 6762 §6). Shared answers to queries arriving while a
+//! batch is pending join that batch, which may hold them for up to 500ms.
+//! Additional records go out along with the answers they came with.
+//!
+//! A record that was multicast less than a second ago isn't scheduled again
+//! (RFC 6762 §6.2), except when defending a name against a probe.
+//!
+//! While an answer is pending, another host may multicast the very same
+//! record; RFC 6762 §7.4 then lets us treat our copy as sent. Duplicate
+//! question suppression (§7.3) has no counterpart here: the only questions
+//! the responder asks are probes, which must always be sent.
+
+use dns_parser::Name;
+use rand::{thread_rng, Rng};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crate::packet::Message;
+use crate::record::{RData, Record};
+
+/// Shortest delay before a shared record is multicast
+pub const RESPONSE_DELAY_MIN: Duration = Duration::from_millis(20);
+
+/// Longest random delay before a shared record is multicast
+pub const RESPONSE_DELAY_MAX: Duration = Duration::from_millis(120);
+
+/// Longest a shared answer may be held back to aggregate it with others
+pub const AGGREGATION_WINDOW: Duration = Duration::from_millis(500);
+
+/// Shortest interval between two multicasts of the same record
+pub const RATE_LIMIT: Duration = Duration::from_secs(1);
+
+/// An answer waiting to be multicast.
+#[derive(Debug)]
+struct Pending {
+    record: Record,
+    /// goes in the additional section rather than among the answers
+    additional: bool,
+    /// when the query this answers arrived
+    queried: Instant,
+    deadline: Instant,
+}
+
+/// Pending multicast answers, keyed by record.
+#[derive(Debug, Default)]
+pub struct PendingResponses {
+    pending: HashMap<(Name<'static>, RData), Pending>,
+    /// when each record was last multicast, for the past second
+    last_multicast: HashMap<(Name<'static>, RData), Instant>,
+}
+
+impl PendingResponses {
+    /// Schedules `record` to answer a query that arrived at `now` and
+    /// returns when it's due.
+    ///
+    /// Records that are already pending keep their place, but move up into
+    /// the answers if they were additional records.
+    pub fn schedule(&mut self, record: Record, now: Instant) -> Instant {
+        let key = (record.name.clone(), record.data.clone());
+        if let Some(pending) = self.pending.get_mut(&key) {
+            pending.additional = false;
+            return pending.deadline;
+        }
+
+        let deadline = if record.unique {
+            now
+        } else {
+            self.shared_deadline(now)
+        };
+        self.pending.insert(
+            key,
+            Pending {
+                record,
+                additional: false,
+                queried: now,
+                deadline,
+            },
+        );
+        deadline
+    }
+
+    /// Schedules `record` as an additional record for answers to a query
+    /// that arrived at `now`, which are due at `deadline`, so that it goes
+    /// out in the same message. Records that are already pending keep their
+    /// place.
+    pub fn schedule_additional(&mut self, record: Record, deadline: Instant, now: Instant) {
+        let key = (record.name.clone(), record.data.clone());
+        self.pending.entry(key).or_insert(Pending {
+            record,
+            additional: true,
+            queried: now,
+            deadline,
+        });
+    }
+
+    /// Picks the deadline for a shared record answering a query that arrived
+    /// at `now`, moving the pending batch along if the record joins it.
+    fn shared_deadline(&mut self, now: Instant) -> Instant {
+        let millis = thread_rng().gen_range(
+            RESPONSE_DELAY_MIN.as_millis() as u64,
+            RESPONSE_DELAY_MAX.as_millis() as u64 + 1,
+        );
+        let delayed = now + Duration::from_millis(millis);
+
+        let batch = self
+            .pending
+            .values()
+            .filter(|pending| !pending.record.unique)
+            .map(|pending| pending.deadline)
+            .min();
+        let batch = match batch {
+            Some(batch) => batch,
+            None => return delayed,
+        };
+
+        // the batch includes the additional records going out with it
+        let oldest = self
+            .pending
+            .values()
+            .filter(|pending| pending.deadline == batch)
+            .map(|pending| pending.queried)
+            .min()
+            .unwrap_or(now);
+        let latest = oldest + AGGREGATION_WINDOW;
+        if now + RESPONSE_DELAY_MIN > latest {
+            // the batch goes out too soon to include this record
+            return delayed;
+        }
+
+        let deadline = std::cmp::max(batch, delayed).min(latest);
+        for pending in self.pending.values_mut() {
+            if pending.deadline == batch {
+                pending.deadline = deadline;
+            }
+        }
+        deadline
+    }
+
+    /// Returns true if `record` was multicast less than a second before
+    /// `now`, in which case it must not be multicast again unless defending
+    /// against a probe.
+    pub fn rate_limited(&self, record: &Record, now: Instant) -> bool {
+        let key = (record.name.clone(), record.data.clone());
+        self.last_multicast
+            .get(&key)
+            .is_some_and(|&sent| now < sent + RATE_LIMIT)
+    }
+
+    /// Notes that `records` were multicast at `now`.
+    pub fn multicast(&mut self, records: &[Record], now: Instant) {
+        self.last_multicast.retain(|_, &mut sent| now < sent + RATE_LIMIT);
+        for record in records {
+            let key = (record.name.clone(), record.data.clone());
+            self.last_multicast.insert(key, now);
+        }
+    }
+
+    /// Cancels our pending copy of a record another host just multicast,
+    /// provided its TTL is at least the one we would have given.
+    ///
+    /// Returns true if an answer was cancelled.
+    pub fn suppress(&mut self, record: &Record) -> bool {
+        let key = (record.name.clone(), record.data.clone());
+        let duplicate = self
+            .pending
+            .get(&key)
+            .is_some_and(|pending| record.ttl >= pending.record.ttl);
+
+        if duplicate {
+            self.pending.remove(&key);
+        }
+        duplicate
+    }
+
+    /// Drops a pending answer for a record we no longer own.
+    pub fn cancel(&mut self, record: &Record) {
+        self.pending.remove(&(record.name.clone(), record.data.clone()));
+    }
+
+    /// Removes all records due at `now` and returns them as a response,
+    /// which is empty if nothing is due.
+    pub fn take_due(&mut self, now: Instant) -> Message {
+        let due: Vec<_> = self
+            .pending
+            .iter()
+            .filter(|(_, pending)| pending.deadline <= now)
+            .map(|(key, _)| key.clone())
+            .collect();
+
+        let mut message = Message::response(0);
+        for pending in due.into_iter().filter_map(|key| self.pending.remove(&key)) {
+            if pending.additional {
+                message.additional.push(pending.record);
+            } else {
+                message.answers.push(pending.record);
+            }
+        }
+        message
+    }
+
+    /// Returns when the next answer is due.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.pending.values().map(|pending| pending.deadline).min()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    fn unique(ttl: u32) -> Record {
+        Record::unique(
+            Name::from_str("host.local").unwrap(),
+            ttl,
+            RData::A(Ipv4Addr::new(10, 0, 0, 1)),
+        )
+    }
+
+    fn shared(instance: &'static str) -> Record {
+        Record::shared(
+            Name::from_str("_http._tcp.local").unwrap(),
+            4500,
+            RData::PTR(Name::from_str(instance).unwrap()),
+        )
+    }
+
+    #[test]
+    fn test_duplicate_answer_suppression() {
+        let mut responses = PendingResponses::default();
+        let now = Instant::now();
+        responses.schedule(unique(120), now);
+
+        assert!(!responses.suppress(&unique(60)));
+        assert!(responses.suppress(&unique(120)));
+        assert!(responses.take_due(now).is_empty());
+        assert_eq!(responses.deadline(), None);
+    }
+
+    #[test]
+    fn test_unique_records_are_due_immediately() {
+        let mut responses = PendingResponses::default();
+        let now = Instant::now();
+        responses.schedule(unique(120), now);
+
+        assert_eq!(responses.take_due(now).answers, vec![unique(120)]);
+    }
+
+    #[test]
+    fn test_shared_records_are_delayed() {
+        let mut responses = PendingResponses::default();
+        let now = Instant::now();
+        responses.schedule(shared("a._http._tcp.local"), now);
+
+        assert!(responses.take_due(now).is_empty());
+        let deadline = responses.deadline().unwrap();
+        assert!(deadline >= now + RESPONSE_DELAY_MIN);
+        assert!(deadline <= now + RESPONSE_DELAY_MAX);
+        assert_eq!(responses.take_due(deadline).answers.len(), 1);
+    }
+
+    #[test]
+    fn test_shared_records_are_aggregated() {
+        let mut responses = PendingResponses::default();
+        let now = Instant::now();
+        responses.schedule(shared("a._http._tcp.local"), now);
+        responses.schedule(shared("b._http._tcp.local"), now + Duration::from_millis(10));
+
+        let deadline = responses.deadline().unwrap();
+        assert!(deadline <= now + AGGREGATION_WINDOW);
+        assert_eq!(responses.take_due(deadline).answers.len(), 2);
+    }
+
+    #[test]
+    fn test_aggregation_window() {
+        let mut responses = PendingResponses::default();
+        let now = Instant::now();
+        responses.schedule(shared("a._http._tcp.local"), now);
+        let first = responses.deadline().unwrap();
+
+        let late = now + AGGREGATION_WINDOW;
+        responses.schedule(shared("b._http._tcp.local"), late);
+
+        assert_eq!(responses.deadline(), Some(first));
+        assert_eq!(responses.take_due(first).answers.len(), 1);
+        assert!(responses.deadline().unwrap() >= late + RESPONSE_DELAY_MIN);
+    }
+
+    #[test]
+    fn test_additional_records() {
+        let mut responses = PendingResponses::default();
+        let now = Instant::now();
+        responses.schedule_additional(unique(120), now, now);
+
+        let message = responses.take_due(now);
+        assert!(message.answers.is_empty());
+        assert_eq!(message.additional, vec![unique(120)]);
+
+        responses.schedule_additional(unique(120), now, now);
+        responses.schedule(unique(120), now);
+        assert_eq!(responses.take_due(now).answers, vec![unique(120)]);
+    }
+
+    #[test]
+    fn test_additional_records_follow_their_answer() {
+        let mut responses = PendingResponses::default();
+        let now = Instant::now();
+        let deadline = responses.schedule(shared("a._http._tcp.local"), now);
+        responses.schedule_additional(unique(120), deadline, now);
+        assert!(responses.take_due(now).is_empty());
+
+        // a second shared answer moves the batch, additional records included
+        responses.schedule(shared("b._http._tcp.local"), now + Duration::from_millis(10));
+        let deadline = responses.deadline().unwrap();
+        let message = responses.take_due(deadline);
+        assert_eq!(message.answers.len(), 2);
+        assert_eq!(message.additional, vec![unique(120)]);
+    }
+
+    #[test]
+    fn test_rate_limit() {
+        let mut responses = PendingResponses::default();
+        let now = Instant::now();
+        responses.multicast(&[unique(120)], now);
+
+        assert!(responses.rate_limited(&unique(120), now + Duration::from_millis(999)));
+        assert!(!responses.rate_limited(&unique(120), now + RATE_LIMIT));
+        assert!(!responses.rate_limited(&shared("a._http._tcp.local"), now));
+    }
+
+    #[test]
+    fn test_cancel() {
+        let mut responses = PendingResponses::default();
+        let now = Instant::now();
+        responses.schedule(shared("a._http._tcp.local"), now);
+        responses.cancel(&shared("a._http._tcp.local"));
+
+        assert_eq!(responses.deadline(), None);
+    }
+}
@@ -3,30 +3,86 @@
 //! This module manages the collection of services that are advertised
 //! via mDNS, including their names, types, ports, and TXT records.
 
-use dns_parser::{self, Name, QueryClass, RRData};
+use dns_parser::Name;
+use log::{error, warn};
 use multimap::MultiMap;
 use rand::{thread_rng, Rng};
 use std::collections::HashMap;
 use std::slice;
-use std::sync::{Arc, RwLock};
+use std::sync::{mpsc, Arc, RwLock};
 
-/// Type alias for DNS answer builder
-pub type AnswerBuilder = dns_parser::Builder<dns_parser::Answers>;
+use crate::config::Ttl;
+use crate::record::{escape_label, is_valid_name, labels, RData, Record, MAX_LABEL_LEN};
+use crate::Event;
 
 /// Thread-safe collection of registered services
 pub type Services = Arc<RwLock<ServicesInner>>;
 
+/// Picks a new instance name after a conflict, given the name the service was
+/// registered with and the attempt number (starting at 2).
+pub type RenamePolicy = Box<dyn Fn(&str, u32) -> String + Send + Sync>;
+
+/// Name that browsers query to enumerate the service types on the network
+/// (RFC 6763 §9)
+pub const SERVICE_TYPES: &str = "_services._dns-sd._udp.local";
+
+/// Returns [`SERVICE_TYPES`](constant.SERVICE_TYPES.html) as a name.
+pub fn service_types_name() -> Name<'static> {
+    Name::from_str(SERVICE_TYPES).expect("Invalid service types name")
+}
+
+/// The default rename policy, producing "Name (2)", "Name (3)", ...
+pub fn numeric_suffix(name: &str, attempt: u32) -> String {
+    format!("{name} ({attempt})")
+}
+
+/// Number of names the rename policy gets to come up with for a single
+/// rename before the default policy takes over
+pub const MAX_RENAME_ATTEMPTS: u32 = 20;
+
+/// Returns the label `make` builds out of `base`, with `base` cut short as
+/// far as needed for the label to fit in
+/// [`MAX_LABEL_LEN`](../record/constant.MAX_LABEL_LEN.html) bytes. Labels
+/// that don't shrink along with `base` may still be too long.
+fn fit_label<F: Fn(&str) -> String>(base: &str, make: F) -> String {
+    let mut end = base.len();
+    loop {
+        let label = make(&base[..end]);
+        if label.len() <= MAX_LABEL_LEN || end == 0 {
+            return label;
+        }
+        end = end.saturating_sub(label.len() - MAX_LABEL_LEN);
+        while !base.is_char_boundary(end) {
+            end -= 1;
+        }
+    }
+}
+
+/// Rename bookkeeping for a service that ran into a name conflict.
+struct Rename {
+    base: String,
+    attempt: u32,
+}
+
 /// The internal service registry.
 ///
 /// Maintains multiple indices for efficient service lookup by ID, name, and type.
 pub struct ServicesInner {
     hostname: Name<'static>,
+    /// set once the host name had to be renamed
+    host_rename: Option<Rename>,
     /// main index
     by_id: HashMap<usize, ServiceData>,
     /// maps to id
     by_type: MultiMap<Name<'static>, usize>,
+    /// subtype names like "_printer._sub._http._tcp.local", maps to id
+    by_subtype: MultiMap<Name<'static>, usize>,
     /// maps to id
     by_name: HashMap<Name<'static>, usize>,
+    /// services that had to be renamed, by id
+    renames: HashMap<usize, Rename>,
+    rename_policy: RenamePolicy,
+    listeners: Vec<mpsc::Sender<Event>>,
 }
 
 impl ServicesInner {
@@ -35,17 +91,69 @@ impl ServicesInner {
         ServicesInner {
             hostname: Name::from_str(hostname)
                 .expect("Invalid hostname format"),
+            host_rename: None,
             by_id: HashMap::new(),
             by_type: MultiMap::new(),
+            by_subtype: MultiMap::new(),
             by_name: HashMap::new(),
+            renames: HashMap::new(),
+            rename_policy: Box::new(numeric_suffix),
+            listeners: Vec::new(),
         }
     }
 
+    /// Returns a channel that receives every [`Event`](../enum.Event.html)
+    /// from now on.
+    pub fn subscribe(&mut self) -> mpsc::Receiver<Event> {
+        let (tx, rx) = mpsc::channel();
+        self.listeners.push(tx);
+        rx
+    }
+
+    /// Sends an event to all listeners, forgetting those that went away.
+    fn notify(&mut self, event: Event) {
+        self.listeners.retain(|tx| tx.send(event.clone()).is_ok());
+    }
+
+    /// Replaces the policy used to pick new instance names after a conflict.
+    pub fn set_rename_policy(&mut self, policy: RenamePolicy) {
+        self.rename_policy = policy;
+    }
+
     /// Returns the hostname for this mDNS responder.
     pub fn get_hostname(&self) -> &Name<'static> {
         &self.hostname
     }
 
+    /// Picks a new host name after a conflict, e.g. "host-2.local", and
+    /// returns it. The original name is cut short where the new one wouldn't
+    /// fit in a label otherwise.
+    pub fn rename_host(&mut self) -> Name<'static> {
+        let hostname = self.hostname.to_string();
+        let rename = self.host_rename.get_or_insert_with(|| Rename {
+            base: hostname.trim_end_matches(".local").to_owned(),
+            attempt: 1,
+        });
+
+        rename.attempt += 1;
+        let attempt = rename.attempt;
+        let label = fit_label(&rename.base, |base| format!("{base}-{attempt}"));
+        let name = Name::from_str(format!("{label}.local")).expect("Invalid hostname format");
+
+        let old = std::mem::replace(&mut self.hostname, name.clone());
+        self.notify(Event::HostnameChanged {
+            old: old.to_string(),
+            new: name.to_string(),
+        });
+
+        name
+    }
+
+    /// Finds a service by the ID returned from [`register`](#method.register).
+    pub fn find_by_id(&self, id: usize) -> Option<&ServiceData> {
+        self.by_id.get(&id)
+    }
+
     /// Finds a service by its fully qualified domain name.
     pub fn find_by_name<'a>(&'a self, name: &'a Name<'a>) -> Option<&'a ServiceData> {
         self.by_name.get(name).and_then(|id| self.by_id.get(id))
@@ -61,7 +169,35 @@ impl ServicesInner {
         }
     }
 
+    /// Returns an iterator over all services registered with the given
+    /// subtype name.
+    pub fn find_by_subtype<'a>(&'a self, subtype: &'a Name<'a>) -> FindByType<'a> {
+        let ids = self.by_subtype.get_vec(subtype).map(|ids| ids.iter());
+
+        FindByType {
+            services: self,
+            ids,
+        }
+    }
+
+    /// Returns an iterator over every service type with at least one
+    /// registered service.
+    pub fn types(&self) -> impl Iterator<Item = &Name<'static>> {
+        self.by_type
+            .iter_all()
+            .filter(|(_, ids)| !ids.is_empty())
+            .map(|(typ, _)| typ)
+    }
+
+    /// Returns an iterator over all registered services and their IDs.
+    pub fn iter(&self) -> impl Iterator<Item = (usize, &ServiceData)> {
+        self.by_id.iter().map(|(&id, svc)| (id, svc))
+    }
+
     /// Registers a new service and returns its unique ID.
+    ///
+    /// If another local service already uses the same name, the new service
+    /// is renamed right away using the rename policy.
     pub fn register(&mut self, svc: ServiceData) -> usize {
         let mut id = thread_rng().gen::<usize>();
         while self.by_id.contains_key(&id) {
@@ -69,12 +205,95 @@ impl ServicesInner {
         }
 
         self.by_type.insert(svc.typ.clone(), id);
-        self.by_name.insert(svc.name.clone(), id);
+        for subtype in &svc.subtypes {
+            self.by_subtype.insert(subtype.clone(), id);
+        }
+        let collides = self.by_name.contains_key(&svc.name);
+        if !collides {
+            self.by_name.insert(svc.name.clone(), id);
+        }
         self.by_id.insert(id, svc);
 
+        // only fails for service types that aren't valid names
+        if collides && self.rename(id).is_none() {
+            panic!("no free name for service of type {}", self.by_id[&id].typ);
+        }
+
         id
     }
 
+    /// Gives a service a new instance name after a conflict and returns it.
+    ///
+    /// The original instance name is cut short where the new one wouldn't
+    /// fit in a label otherwise. Names already used by other local services
+    /// are skipped. If the rename policy comes up with no usable name in
+    /// [`MAX_RENAME_ATTEMPTS`](constant.MAX_RENAME_ATTEMPTS.html) attempts,
+    /// [`numeric_suffix`](fn.numeric_suffix.html) takes over, which always
+    /// finds a free name for a service type that is valid. Returns `None` if
+    /// the service doesn't exist or no usable name was found at all.
+    pub fn rename(&mut self, id: usize) -> Option<Name<'static>> {
+        // one more numbered name than there are names taken
+        let numeric_attempts = self.by_name.len() as u32 + 1;
+        let svc = self.by_id.get_mut(&id)?;
+        let rename = self.renames.entry(id).or_insert_with(|| Rename {
+            base: svc.instance(),
+            attempt: 1,
+        });
+
+        let mut found = None;
+        for tries in 0..MAX_RENAME_ATTEMPTS + numeric_attempts {
+            if tries == MAX_RENAME_ATTEMPTS {
+                warn!("rename policy found no usable name for {}, adding a number", svc.name);
+            }
+            rename.attempt += 1;
+            let attempt = rename.attempt;
+            let instance = if tries < MAX_RENAME_ATTEMPTS {
+                let policy = &self.rename_policy;
+                fit_label(&rename.base, |base| policy(base, attempt))
+            } else {
+                fit_label(&rename.base, |base| numeric_suffix(base, attempt))
+            };
+            let name = Name::from_str(format!("{}.{}", escape_label(&instance), svc.typ));
+            match name {
+                Ok(name) if is_valid_name(&name) && !self.by_name.contains_key(&name) => {
+                    found = Some(name);
+                    break;
+                }
+                _ => (),
+            }
+        }
+        let name = match found {
+            Some(name) => name,
+            None => {
+                error!("could not find a new name for {}", svc.name);
+                return None;
+            }
+        };
+
+        if self.by_name.get(&svc.name) == Some(&id) {
+            self.by_name.remove(&svc.name);
+        }
+        self.by_name.insert(name.clone(), id);
+        let old = std::mem::replace(&mut svc.name, name.clone());
+
+        self.notify(Event::ServiceRenamed {
+            old: old.to_string(),
+            new: name.to_string(),
+        });
+
+        Some(name)
+    }
+
+    /// Sets the TTLs a service's records use instead of the configured ones.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the service ID doesn't exist.
+    pub fn set_ttl(&mut self, id: usize, ttl: Ttl) {
+        let svc = self.by_id.get_mut(&id).expect("unknown service");
+        svc.ttl = Some(ttl);
+    }
+
     /// Unregisters a service by ID and returns its data.
     ///
     /// # Panics
@@ -85,10 +304,22 @@ impl ServicesInner {
 
         if let Some(entries) = self.by_type.get_vec_mut(&svc.typ) {
             entries.retain(|&e| e != id);
+            if entries.is_empty() {
+                self.by_type.remove(&svc.typ);
+            }
+        }
+        for subtype in &svc.subtypes {
+            if let Some(entries) = self.by_subtype.get_vec_mut(subtype) {
+                entries.retain(|&e| e != id);
+                if entries.is_empty() {
+                    self.by_subtype.remove(subtype);
+                }
+            }
         }
 
         let removed = self.by_name.remove(&svc.name);
         assert_eq!(removed, Some(id), "Service name index mismatch for id {id}");
+        self.renames.remove(&id);
 
         svc
     }
@@ -117,26 +348,47 @@ pub struct ServiceData {
     pub typ: Name<'static>,
     pub port: u16,
     pub txt: Vec<u8>,
+    /// subtype names the service is also listed under (RFC 6763 §7.1)
+    pub subtypes: Vec<Name<'static>>,
+    /// TTLs overriding the configured ones for the service's records
+    pub ttl: Option<Ttl>,
 }
 
 impl ServiceData {
-    /// Adds a PTR record for this service to the answer builder.
-    pub fn add_ptr_rr(&self, builder: AnswerBuilder, ttl: u32) -> AnswerBuilder {
-        builder.add_answer(
-            &self.typ,
-            QueryClass::IN,
-            ttl,
-            &RRData::PTR(self.name.clone()),
-        )
+    /// Returns the instance part of the service name, e.g. "My Web Server".
+    pub fn instance(&self) -> String {
+        match labels(&self.name).first() {
+            Some(label) => String::from_utf8_lossy(label).into_owned(),
+            None => String::new(),
+        }
     }
 
-    /// Adds an SRV record for this service to the answer builder.
-    pub fn add_srv_rr(&self, hostname: &Name, builder: AnswerBuilder, ttl: u32) -> AnswerBuilder {
-        builder.add_answer(
-            &self.name,
-            QueryClass::IN,
+    /// Returns the PTR record pointing from the service type to this service.
+    pub fn ptr_record(&self, ttl: u32) -> Record {
+        Record::shared(self.typ.clone(), ttl, RData::PTR(self.name.clone()))
+    }
+
+    /// Returns the PTR records pointing from each of the service's subtypes
+    /// to this service.
+    pub fn subtype_ptr_records(&self, ttl: u32) -> Vec<Record> {
+        self.subtypes
+            .iter()
+            .map(|subtype| Record::shared(subtype.clone(), ttl, RData::PTR(self.name.clone())))
+            .collect()
+    }
+
+    /// Returns the PTR record listing this service's type among the service
+    /// types on the network.
+    pub fn type_ptr_record(&self, ttl: u32) -> Record {
+        Record::shared(service_types_name(), ttl, RData::PTR(self.typ.clone()))
+    }
+
+    /// Returns the SRV record for this service.
+    pub fn srv_record(&self, hostname: &Name<'static>, ttl: u32) -> Record {
+        Record::unique(
+            self.name.clone(),
             ttl,
-            &RRData::SRV {
+            RData::SRV {
                 priority: 0,
                 weight: 0,
                 port: self.port,
@@ -145,9 +397,19 @@ impl ServiceData {
         )
     }
 
-    /// Adds a TXT record for this service to the answer builder.
-    pub fn add_txt_rr(&self, builder: AnswerBuilder, ttl: u32) -> AnswerBuilder {
-        builder.add_answer(&self.name, QueryClass::IN, ttl, &RRData::TXT(&self.txt))
+    /// Returns the TXT record for this service.
+    pub fn txt_record(&self, ttl: u32) -> Record {
+        Record::unique(self.name.clone(), ttl, RData::TXT(self.txt.clone()))
+    }
+
+    /// Returns the NSEC record stating that the instance name has SRV and
+    /// TXT records only.
+    pub fn nsec_record(&self, hostname: &Name<'static>, ttl: u32) -> Record {
+        let types = vec![
+            self.srv_record(hostname, ttl).data.rtype(),
+            self.txt_record(ttl).data.rtype(),
+        ];
+        Record::nsec(self.name.clone(), ttl, types)
     }
 }
 
@@ -157,12 +419,14 @@ mod tests {
 
     fn create_test_service(name: &str, typ: &str, port: u16) -> ServiceData {
         ServiceData {
-            name: Name::from_str(format!("{}.{}.local", name, typ))
+            name: Name::from_str(format!("{}.{}.local", escape_label(name), typ))
                 .expect("Invalid test name"),
             typ: Name::from_str(format!("{}.local", typ))
                 .expect("Invalid test type"),
             port,
             txt: vec![0],
+            subtypes: Vec::new(),
+            ttl: None,
         }
     }
 
@@ -202,6 +466,42 @@ mod tests {
         assert_eq!(services.by_name.len(), 0);
     }
 
+    #[test]
+    fn test_types() {
+        let mut services = ServicesInner::new("test-host.local".to_string());
+        let web = services.register(create_test_service("web", "_http._tcp", 80));
+        services.register(create_test_service("other web", "_http._tcp", 8080));
+        let printer = services.register(create_test_service("printer", "_ipp._tcp", 631));
+
+        let mut types: Vec<String> = services.types().map(|typ| typ.to_string()).collect();
+        types.sort();
+        assert_eq!(types, vec!["_http._tcp.local", "_ipp._tcp.local"]);
+
+        services.unregister(web);
+        services.unregister(printer);
+        let types: Vec<String> = services.types().map(|typ| typ.to_string()).collect();
+        assert_eq!(types, vec!["_http._tcp.local"]);
+    }
+
+    #[test]
+    fn test_find_by_subtype() {
+        let mut services = ServicesInner::new("test-host.local".to_string());
+        let subtype = Name::from_str("_printer._sub._http._tcp.local").unwrap();
+        let mut svc = create_test_service("web", "_http._tcp", 80);
+        svc.subtypes.push(subtype.clone());
+
+        let id = services.register(svc.clone());
+        services.register(create_test_service("other web", "_http._tcp", 8080));
+
+        let found: Vec<_> = services.find_by_subtype(&subtype).collect();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].name, svc.name);
+        assert_eq!(svc.subtype_ptr_records(120)[0].name, subtype);
+
+        services.unregister(id);
+        assert_eq!(services.find_by_subtype(&subtype).count(), 0);
+    }
+
     #[test]
     #[should_panic(expected = "unknown service")]
     fn test_unregister_unknown_service() {
@@ -209,6 +509,31 @@ mod tests {
         services.unregister(12345);
     }
 
+    #[test]
+    fn test_find_by_id() {
+        let mut services = ServicesInner::new("test-host.local".to_string());
+        let svc = create_test_service("myservice", "_http._tcp", 8080);
+
+        let id = services.register(svc.clone());
+
+        assert_eq!(services.find_by_id(id).unwrap().name, svc.name);
+        assert!(services.find_by_id(id.wrapping_add(1)).is_none());
+    }
+
+    #[test]
+    fn test_set_ttl() {
+        let mut services = ServicesInner::new("test-host.local".to_string());
+        let id = services.register(create_test_service("myservice", "_http._tcp", 8080));
+        assert_eq!(services.find_by_id(id).unwrap().ttl, None);
+
+        let ttl = Ttl {
+            txt: 600,
+            ..Ttl::default()
+        };
+        services.set_ttl(id, ttl);
+        assert_eq!(services.find_by_id(id).unwrap().ttl, Some(ttl));
+    }
+
     #[test]
     fn test_find_by_name() {
         let mut services = ServicesInner::new("test-host.local".to_string());
@@ -264,6 +589,142 @@ mod tests {
         assert_eq!(found.len(), 5);
     }
 
+    #[test]
+    fn test_register_local_collision_renames() {
+        let mut services = ServicesInner::new("test-host.local".to_string());
+        let svc = create_test_service("myservice", "_http._tcp", 8080);
+
+        let first = services.register(svc.clone());
+        let second = services.register(svc.clone());
+
+        assert_eq!(services.find_by_id(first).unwrap().name, svc.name);
+        assert_eq!(services.find_by_id(second).unwrap().instance(), "myservice (2)");
+        assert_eq!(services.by_name.len(), 2);
+
+        services.unregister(second);
+        services.unregister(first);
+    }
+
+    #[test]
+    fn test_rename_increments_suffix() {
+        let mut services = ServicesInner::new("test-host.local".to_string());
+        let id = services.register(create_test_service("printer", "_ipp._tcp", 631));
+
+        services.rename(id);
+        let name = services.rename(id).unwrap();
+
+        assert_eq!(name.to_string(), "printer (3)._ipp._tcp.local");
+        assert!(services.find_by_name(&name).is_some());
+        assert_eq!(services.by_name.len(), 1);
+    }
+
+    #[test]
+    fn test_custom_rename_policy() {
+        let mut services = ServicesInner::new("test-host.local".to_string());
+        services.set_rename_policy(Box::new(|name, attempt| format!("{name}-{attempt}")));
+        let id = services.register(create_test_service("printer", "_ipp._tcp", 631));
+
+        let name = services.rename(id).unwrap();
+        assert_eq!(name.to_string(), "printer-2._ipp._tcp.local");
+    }
+
+    #[test]
+    fn test_rename_policy_always_colliding() {
+        let mut services = ServicesInner::new("test-host.local".to_string());
+        services.set_rename_policy(Box::new(|_, _| "taken".to_owned()));
+        services.register(create_test_service("taken", "_ipp._tcp", 631));
+        let id = services.register(create_test_service("printer", "_ipp._tcp", 631));
+
+        // the policy used up attempts 2 to 21
+        let name = services.rename(id).unwrap();
+        assert_eq!(name.to_string(), "printer (22)._ipp._tcp.local");
+    }
+
+    #[test]
+    fn test_rename_long_name() {
+        let mut services = ServicesInner::new("test-host.local".to_string());
+        let long = "p".repeat(MAX_LABEL_LEN);
+        let first = services.register(create_test_service(&long, "_ipp._tcp", 631));
+        let second = services.register(create_test_service(&long, "_ipp._tcp", 631));
+
+        let renamed = services.find_by_id(second).unwrap().name.clone();
+        assert_eq!(renamed.to_string(), format!("{} (2)._ipp._tcp.local", "p".repeat(59)));
+        assert_eq!(services.by_name.len(), 2);
+
+        services.unregister(second);
+        services.unregister(first);
+        assert!(services.by_name.is_empty());
+    }
+
+    #[test]
+    fn test_fit_label() {
+        assert_eq!(fit_label("printer", |base| numeric_suffix(base, 2)), "printer (2)");
+        let long = "é".repeat(40);
+        let label = fit_label(&long, |base| numeric_suffix(base, 10));
+        assert!(label.len() <= MAX_LABEL_LEN);
+        assert!(label.ends_with(" (10)"));
+    }
+
+    #[test]
+    fn test_rename_event() {
+        let mut services = ServicesInner::new("test-host.local".to_string());
+        let events = services.subscribe();
+        let id = services.register(create_test_service("printer", "_ipp._tcp", 631));
+
+        services.rename(id);
+        assert_eq!(
+            events.try_recv().unwrap(),
+            Event::ServiceRenamed {
+                old: "printer._ipp._tcp.local".to_owned(),
+                new: "printer (2)._ipp._tcp.local".to_owned(),
+            }
+        );
+    }
+
+    #[test]
+    fn test_rename_host() {
+        let mut services = ServicesInner::new("test-host.local".to_string());
+        let events = services.subscribe();
+
+        assert_eq!(services.rename_host().to_string(), "test-host-2.local");
+        assert_eq!(services.rename_host().to_string(), "test-host-3.local");
+        assert_eq!(services.get_hostname().to_string(), "test-host-3.local");
+
+        assert_eq!(
+            events.try_recv().unwrap(),
+            Event::HostnameChanged {
+                old: "test-host.local".to_owned(),
+                new: "test-host-2.local".to_owned(),
+            }
+        );
+    }
+
+    #[test]
+    fn test_rename_long_host() {
+        let long = "h".repeat(MAX_LABEL_LEN);
+        let mut services = ServicesInner::new(format!("{long}.local"));
+
+        let name = services.rename_host();
+        assert!(is_valid_name(&name));
+        assert_eq!(name.to_string(), format!("{}-2.local", "h".repeat(61)));
+    }
+
+    #[test]
+    fn test_service_data_instance() {
+        let svc = create_test_service("My Server", "_http._tcp", 80);
+        assert_eq!(svc.instance(), "My Server");
+    }
+
+    #[test]
+    fn test_instance_with_dot() {
+        let mut services = ServicesInner::new("test-host.local".to_string());
+        let id = services.register(create_test_service("My.Printer", "_ipp._tcp", 631));
+        assert_eq!(services.find_by_id(id).unwrap().instance(), "My.Printer");
+
+        services.rename(id);
+        assert_eq!(services.find_by_id(id).unwrap().instance(), "My.Printer (2)");
+    }
+
     #[test]
     fn test_service_data_clone() {
         let svc = create_test_service("test", "_http._tcp", 8080);
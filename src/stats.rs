@@ -0,0 +1,48 @@
+//! Counters for answers the responder chose not to send.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// How many answers the responder left out, and why, as returned by
+/// [`Responder::statistics`](struct.Responder.html#method.statistics).
+///
+/// The counts cover both address families and only ever grow.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Statistics {
+    /// Answers the querier already listed as known answers (RFC 6762 §7.1).
+    pub known_answers: u64,
+    /// Pending answers another host multicast first (RFC 6762 §7.4).
+    pub duplicate_answers: u64,
+    /// Answers not multicast because the record already went out less than
+    /// a second earlier (RFC 6762 §6.2).
+    pub rate_limited: u64,
+}
+
+/// Counters shared between the responder and its state machines.
+#[derive(Debug, Default)]
+pub(crate) struct Counters {
+    known_answers: AtomicU64,
+    duplicate_answers: AtomicU64,
+    rate_limited: AtomicU64,
+}
+
+impl Counters {
+    pub fn add_known_answers(&self, count: usize) {
+        self.known_answers.fetch_add(count as u64, Ordering::Relaxed);
+    }
+
+    pub fn add_duplicate_answer(&self) {
+        self.duplicate_answers.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn add_rate_limited(&self) {
+        self.rate_limited.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> Statistics {
+        Statistics {
+            known_answers: self.known_answers.load(Ordering::Relaxed),
+            duplicate_answers: self.duplicate_answers.load(Ordering::Relaxed),
+            rate_limited: self.rate_limited.load(Ordering::Relaxed),
+        }
+    }
+}
@@ -0,0 +1,163 @@
+//! Queries whose known answers span several packets (RFC 6762 §7.2).
+//!
+//! A querier with more known answers than fit into one packet sets the TC bit
+//! and sends the rest in follow-up packets without questions. Answering right
+//! away would ignore those known answers, so the query is held for 400–500ms
+//! while the follow-ups from the same source are collected.
+//!
+//! Each truncated follow-up extends the wait, but only up to a few seconds
+//! after the query arrived, and only so many known answers are collected;
+//! the query is answered once either runs out.
+
+use rand::{thread_rng, Rng};
+use std::time::{Duration, Instant};
+
+use crate::address_family::PacketInfo;
+use crate::record::Record;
+
+/// Shortest time a truncated query waits for its follow-up packets
+pub const TRUNCATED_DELAY_MIN: Duration = Duration::from_millis(400);
+
+/// Longest time a truncated query waits for its follow-up packets
+pub const TRUNCATED_DELAY_MAX: Duration = Duration::from_millis(500);
+
+/// Longest time a truncated query waits in total, however many truncated
+/// follow-ups arrive
+pub const TRUNCATED_WAIT_MAX: Duration = Duration::from_secs(2);
+
+/// Most known answers collected for a truncated query
+pub const MAX_KNOWN_ANSWERS: usize = 1024;
+
+/// A truncated query waiting for the rest of its known answers.
+#[derive(Debug)]
+pub struct TruncatedQuery {
+    /// the query packet as received, parsed again once it's answered
+    query: Vec<u8>,
+    /// where the query arrived, if known
+    info: Option<PacketInfo>,
+    known_answers: Vec<Record>,
+    /// when the query arrived
+    received: Instant,
+    deadline: Instant,
+}
+
+impl TruncatedQuery {
+    /// Holds `query`, which arrived as described by `info`, whose own known
+    /// answers are `known_answers`.
+    pub fn new(
+        query: &[u8],
+        info: Option<PacketInfo>,
+        known_answers: Vec<Record>,
+    ) -> TruncatedQuery {
+        let received = Instant::now();
+        let mut query = TruncatedQuery {
+            query: query.to_vec(),
+            info,
+            known_answers: Vec::new(),
+            received,
+            deadline: received + random_delay(),
+        };
+        query.add_known_answers(known_answers, false);
+        query
+    }
+
+    /// Adds the known answers of a follow-up packet. If that packet is
+    /// truncated as well, more are on their way and the query keeps waiting,
+    /// up to [`TRUNCATED_WAIT_MAX`] after it arrived.
+    ///
+    /// Returns true if the query collected [`MAX_KNOWN_ANSWERS`] and is to be
+    /// answered right away. Known answers beyond those are dropped.
+    ///
+    /// [`TRUNCATED_WAIT_MAX`]: constant.TRUNCATED_WAIT_MAX.html
+    /// [`MAX_KNOWN_ANSWERS`]: constant.MAX_KNOWN_ANSWERS.html
+    pub fn add_known_answers(&mut self, known_answers: Vec<Record>, truncated: bool) -> bool {
+        let room = MAX_KNOWN_ANSWERS - self.known_answers.len();
+        let full = known_answers.len() >= room;
+        self.known_answers.extend(known_answers.into_iter().take(room));
+        if truncated {
+            let latest = self.received + TRUNCATED_WAIT_MAX;
+            self.deadline = (Instant::now() + random_delay()).min(latest);
+        }
+        full
+    }
+
+    /// Returns when the query is answered.
+    pub fn deadline(&self) -> Instant {
+        self.deadline
+    }
+
+    /// Returns the query packet, where it arrived and every known answer
+    /// collected for it.
+    pub fn into_parts(self) -> (Vec<u8>, Option<PacketInfo>, Vec<Record>) {
+        (self.query, self.info, self.known_answers)
+    }
+}
+
+fn random_delay() -> Duration {
+    let millis = thread_rng().gen_range(
+        TRUNCATED_DELAY_MIN.as_millis() as u64,
+        TRUNCATED_DELAY_MAX.as_millis() as u64 + 1,
+    );
+    Duration::from_millis(millis)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::record::RData;
+    use dns_parser::Name;
+    use std::net::Ipv4Addr;
+
+    fn known(last: u8) -> Record {
+        Record::shared(
+            Name::from_str("host.local").unwrap(),
+            120,
+            RData::A(Ipv4Addr::new(10, 0, 0, last)),
+        )
+    }
+
+    #[test]
+    fn test_accumulate_known_answers() {
+        let start = Instant::now();
+        let mut query = TruncatedQuery::new(&[1, 2, 3], None, vec![known(1)]);
+        assert!(query.deadline() >= start + TRUNCATED_DELAY_MIN);
+
+        query.add_known_answers(vec![known(2), known(3)], false);
+        let (packet, _, known_answers) = query.into_parts();
+        assert_eq!(packet, vec![1, 2, 3]);
+        assert_eq!(known_answers, vec![known(1), known(2), known(3)]);
+    }
+
+    #[test]
+    fn test_follow_up_deadline() {
+        let mut query = TruncatedQuery::new(&[], None, Vec::new());
+        let first = query.deadline();
+
+        query.add_known_answers(Vec::new(), false);
+        assert_eq!(query.deadline(), first);
+
+        let now = Instant::now();
+        query.add_known_answers(Vec::new(), true);
+        assert!(query.deadline() >= now + TRUNCATED_DELAY_MIN);
+    }
+
+    #[test]
+    fn test_wait_limit() {
+        let mut query = TruncatedQuery::new(&[], None, Vec::new());
+        query.received -= TRUNCATED_WAIT_MAX;
+
+        query.add_known_answers(Vec::new(), true);
+        assert!(query.deadline() <= Instant::now());
+    }
+
+    #[test]
+    fn test_known_answer_limit() {
+        let mut query = TruncatedQuery::new(&[], None, vec![known(0); MAX_KNOWN_ANSWERS - 2]);
+        assert!(!query.add_known_answers(vec![known(1)], true));
+        assert!(query.add_known_answers(vec![known(2), known(3)], true));
+
+        let (_, _, known_answers) = query.into_parts();
+        assert_eq!(known_answers.len(), MAX_KNOWN_ANSWERS);
+        assert_eq!(known_answers.last(), Some(&known(2)));
+    }
+}
@@ -0,0 +1,223 @@
+//! Notifications about interfaces and addresses coming and going.
+//!
+//! On Linux, a netlink route socket subscribed to link and address changes
+//! wakes the state machine, which then compares the interfaces with the ones
+//! it joined the mDNS group on. Other platforms don't notice such changes,
+//! and neither does Linux where netlink sockets aren't allowed, as in some
+//! sandboxes and containers.
+
+#[cfg(target_os = "linux")]
+use log::{error, warn};
+#[cfg(target_os = "linux")]
+use std::io;
+use tokio_core::reactor::Handle;
+
+/// Watches for interfaces and addresses being added or removed.
+#[cfg(target_os = "linux")]
+pub struct Watcher {
+    /// `None` if watching isn't possible, or failed
+    socket: Option<tokio_core::reactor::PollEvented<NetlinkSocket>>,
+}
+
+#[cfg(target_os = "linux")]
+impl Watcher {
+    /// Starts watching, or logs why that isn't possible and returns a
+    /// watcher that never reports a change.
+    pub fn new(handle: &Handle) -> Watcher {
+        let socket = NetlinkSocket::new()
+            .and_then(|socket| tokio_core::reactor::PollEvented::new(socket, handle));
+        match socket {
+            Ok(socket) => Watcher {
+                socket: Some(socket),
+            },
+            Err(err) => {
+                warn!("can't watch for interface changes: {err}");
+                Watcher { socket: None }
+            }
+        }
+    }
+
+    /// Returns whether changes are being watched for.
+    pub fn is_active(&self) -> bool {
+        self.socket.is_some()
+    }
+
+    /// Reads the notifications received so far and returns whether any of
+    /// them reported a change. Must be called from within the task, which is
+    /// woken up when more arrive.
+    ///
+    /// If reading fails, watching stops for good.
+    pub fn poll_changed(&mut self) -> bool {
+        let mut buf = [0u8; 8192];
+        let mut changed = false;
+        while let Some(ref socket) = self.socket {
+            if !socket.poll_read().is_ready() {
+                break;
+            }
+            match socket.get_ref().recv(&mut buf) {
+                Ok(len) => changed |= has_change(&buf[..len]),
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    socket.need_read();
+                    break;
+                }
+                // notifications were dropped, so anything may have changed
+                Err(ref err) if err.raw_os_error() == Some(libc::ENOBUFS) => changed = true,
+                Err(err) => {
+                    error!("stopped watching for interface changes: {err}");
+                    self.socket = None;
+                }
+            }
+        }
+        changed
+    }
+}
+
+/// Interface and address changes can't be watched for on this platform.
+#[cfg(not(target_os = "linux"))]
+pub struct Watcher;
+
+#[cfg(not(target_os = "linux"))]
+impl Watcher {
+    pub fn new(_handle: &Handle) -> Watcher {
+        Watcher
+    }
+
+    pub fn is_active(&self) -> bool {
+        false
+    }
+
+    pub fn poll_changed(&mut self) -> bool {
+        false
+    }
+}
+
+/// A non-blocking netlink route socket subscribed to link and address
+/// changes of both families.
+#[cfg(target_os = "linux")]
+#[derive(Debug)]
+struct NetlinkSocket {
+    fd: std::os::unix::io::RawFd,
+}
+
+#[cfg(target_os = "linux")]
+impl NetlinkSocket {
+    fn new() -> io::Result<NetlinkSocket> {
+        let kind = libc::SOCK_RAW | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC;
+        let fd = unsafe { libc::socket(libc::AF_NETLINK, kind, libc::NETLINK_ROUTE) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let socket = NetlinkSocket { fd };
+
+        let groups = libc::RTMGRP_LINK | libc::RTMGRP_IPV4_IFADDR | libc::RTMGRP_IPV6_IFADDR;
+        let ret = unsafe {
+            let mut addr: libc::sockaddr_nl = std::mem::zeroed();
+            addr.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+            addr.nl_groups = groups as u32;
+            libc::bind(
+                fd,
+                &addr as *const libc::sockaddr_nl as *const libc::sockaddr,
+                std::mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(socket)
+    }
+
+    fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = unsafe { libc::recv(self.fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len(), 0) };
+        if len < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(len as usize)
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Drop for NetlinkSocket {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.fd);
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl mio::Evented for NetlinkSocket {
+    fn register(
+        &self,
+        poll: &mio::Poll,
+        token: mio::Token,
+        interest: mio::Ready,
+        opts: mio::PollOpt,
+    ) -> io::Result<()> {
+        mio::unix::EventedFd(&self.fd).register(poll, token, interest, opts)
+    }
+
+    fn reregister(
+        &self,
+        poll: &mio::Poll,
+        token: mio::Token,
+        interest: mio::Ready,
+        opts: mio::PollOpt,
+    ) -> io::Result<()> {
+        mio::unix::EventedFd(&self.fd).reregister(poll, token, interest, opts)
+    }
+
+    fn deregister(&self, poll: &mio::Poll) -> io::Result<()> {
+        mio::unix::EventedFd(&self.fd).deregister(poll)
+    }
+}
+
+/// Returns whether the netlink messages in `buf` report a link or address
+/// being added, removed or changed.
+#[cfg(target_os = "linux")]
+fn has_change(mut buf: &[u8]) -> bool {
+    let header_len = std::mem::size_of::<libc::nlmsghdr>();
+    while buf.len() >= header_len {
+        let header = unsafe { std::ptr::read_unaligned(buf.as_ptr() as *const libc::nlmsghdr) };
+        match header.nlmsg_type {
+            libc::RTM_NEWLINK | libc::RTM_DELLINK | libc::RTM_NEWADDR | libc::RTM_DELADDR => {
+                return true
+            }
+            _ => (),
+        }
+
+        // messages are padded to four bytes
+        let len = (header.nlmsg_len as usize + 3) & !3;
+        if len < header_len {
+            break;
+        }
+        buf = &buf[len.min(buf.len())..];
+    }
+    false
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+
+    fn message(kind: u16, payload: usize) -> Vec<u8> {
+        let len = std::mem::size_of::<libc::nlmsghdr>() + payload;
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&(len as u32).to_ne_bytes());
+        buf.extend_from_slice(&kind.to_ne_bytes());
+        buf.extend_from_slice(&[0; 10]);
+        buf.resize(len, 0);
+        buf
+    }
+
+    #[test]
+    fn test_has_change() {
+        assert!(!has_change(&[]));
+        assert!(!has_change(&message(libc::NLMSG_DONE as u16, 4)));
+        assert!(has_change(&message(libc::RTM_NEWADDR, 8)));
+
+        let mut buf = message(libc::RTM_NEWROUTE, 6);
+        buf.resize(buf.len() + 2, 0);
+        buf.extend(message(libc::RTM_DELLINK, 16));
+        assert!(has_change(&buf));
+    }
+}
@@ -97,4 +97,40 @@ fn test_service_with_special_characters() {
     );
     
     thread::sleep(Duration::from_millis(100));
-}
\ No newline at end of file
+}
+
+#[test]
+fn test_service_name() {
+    let responder = mdns::Responder::new().expect("Failed to create responder");
+
+    let service = responder.register(
+        "_named._tcp".to_owned(),
+        "Named Service".to_owned(),
+        4444,
+        &[],
+    );
+    let duplicate = responder.register(
+        "_named._tcp".to_owned(),
+        "Named Service".to_owned(),
+        4445,
+        &[],
+    );
+
+    assert_eq!(service.name(), "Named Service");
+    assert_eq!(duplicate.name(), "Named Service (2)");
+}
+
+#[test]
+fn test_register_with_subtypes() {
+    let responder = mdns::Responder::new().expect("Failed to create responder");
+
+    let service = responder.register_with_subtypes(
+        "_http._tcp".to_owned(),
+        "Subtyped Service".to_owned(),
+        8088,
+        &["path=/"],
+        &["_printer", "_api"],
+    );
+
+    assert_eq!(service.name(), "Subtyped Service");
+}